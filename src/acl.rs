@@ -0,0 +1,864 @@
+//! ACL endpoint
+
+use std::collections::HashMap;
+
+use futures::{future, Future};
+use hyper::Method;
+use hyper::header::ContentType;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use {append_query, bool_response, escape, json_response, unit_response, Client, Error, QueryOptions, WriteOptions};
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// Reference to a policy or a role, by ID or by name
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AclLink {
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "ID")]
+    pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "Name")]
+    pub name: String,
+}
+
+impl AclLink {
+    pub fn id(id: &str) -> Self {
+        AclLink { id: id.to_string(), ..Default::default() }
+    }
+
+    pub fn name(name: &str) -> Self {
+        AclLink { name: name.to_string(), ..Default::default() }
+    }
+}
+
+/// Grants the permissions a service needs to register itself and discover
+/// others
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AclServiceIdentity {
+    #[serde(rename = "ServiceName")]
+    pub service_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "Datacenters")]
+    pub datacenters: Option<Vec<String>>,
+}
+
+/// Grants the permissions an agent needs on its own node
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AclNodeIdentity {
+    #[serde(rename = "NodeName")]
+    pub node_name: String,
+    #[serde(rename = "Datacenter")]
+    pub datacenter: String,
+}
+
+/// AclToken is a token along with the permissions linked to it.
+///
+/// Lists leave out `secret_id`. Fields left empty are not sent, so that
+/// `..Default::default()` can be used to create tokens.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AclToken {
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "AccessorID")]
+    pub accessor_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "SecretID")]
+    pub secret_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "Description")]
+    pub description: String,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "Policies")]
+    pub policies: Option<Vec<AclLink>>,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "Roles")]
+    pub roles: Option<Vec<AclLink>>,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "ServiceIdentities")]
+    pub service_identities: Option<Vec<AclServiceIdentity>>,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "NodeIdentities")]
+    pub node_identities: Option<Vec<AclNodeIdentity>>,
+    /// Only valid in the datacenter it was created in
+    #[serde(default, rename = "Local")]
+    pub local: bool,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "AuthMethod")]
+    pub auth_method: String,
+    /// Lifetime of a new token, e.g. `"24h"`
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "ExpirationTTL")]
+    pub expiration_ttl: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "ExpirationTime")]
+    pub expiration_time: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "CreateTime")]
+    pub create_time: String,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "Hash")]
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "is_zero", rename = "CreateIndex")]
+    pub create_index: u64,
+    #[serde(default, skip_serializing_if = "is_zero", rename = "ModifyIndex")]
+    pub modify_index: u64,
+}
+
+/// AclPolicy is a set of rules, lists leave out the rules
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AclPolicy {
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "ID")]
+    pub id: String,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "Description")]
+    pub description: String,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "Rules")]
+    pub rules: String,
+    /// Datacenters the policy is enforced in, all of them if unset
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "Datacenters")]
+    pub datacenters: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "Hash")]
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "is_zero", rename = "CreateIndex")]
+    pub create_index: u64,
+    #[serde(default, skip_serializing_if = "is_zero", rename = "ModifyIndex")]
+    pub modify_index: u64,
+}
+
+/// AclRole is a named set of policies and identities tokens can link to
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AclRole {
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "ID")]
+    pub id: String,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "Description")]
+    pub description: String,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "Policies")]
+    pub policies: Option<Vec<AclLink>>,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "ServiceIdentities")]
+    pub service_identities: Option<Vec<AclServiceIdentity>>,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "NodeIdentities")]
+    pub node_identities: Option<Vec<AclNodeIdentity>>,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "Hash")]
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "is_zero", rename = "CreateIndex")]
+    pub create_index: u64,
+    #[serde(default, skip_serializing_if = "is_zero", rename = "ModifyIndex")]
+    pub modify_index: u64,
+}
+
+/// What a binding rule grants to the tokens created by a login
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BindType {
+    /// A service identity named `bind_name`
+    #[default]
+    Service,
+    /// A node identity named `bind_name`
+    Node,
+    /// The role named `bind_name`
+    Role,
+    /// The policy named `bind_name`
+    Policy,
+}
+
+/// AclBindingRule maps identities of an auth method to permissions
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AclBindingRule {
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "ID")]
+    pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "Description")]
+    pub description: String,
+    #[serde(rename = "AuthMethod")]
+    pub auth_method: String,
+    /// Filter on the identity's attributes, empty to match everything
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "Selector")]
+    pub selector: String,
+    #[serde(rename = "BindType")]
+    pub bind_type: BindType,
+    /// Name to bind, may interpolate the identity's attributes
+    #[serde(rename = "BindName")]
+    pub bind_name: String,
+    #[serde(default, skip_serializing_if = "is_zero", rename = "CreateIndex")]
+    pub create_index: u64,
+    #[serde(default, skip_serializing_if = "is_zero", rename = "ModifyIndex")]
+    pub modify_index: u64,
+}
+
+/// AclAuthMethod lets an external identity provider log in to consul
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AclAuthMethod {
+    #[serde(rename = "Name")]
+    pub name: String,
+    /// `kubernetes`, `jwt`, `oidc`...
+    #[serde(rename = "Type")]
+    pub type_: String,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "DisplayName")]
+    pub display_name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "Description")]
+    pub description: String,
+    /// Lifetime of the tokens created by a login, e.g. `"8h"`
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "MaxTokenTTL")]
+    pub max_token_ttl: Option<String>,
+    /// `local` or `global`
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "TokenLocality")]
+    pub token_locality: Option<String>,
+    /// Settings specific to the type
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "Config")]
+    pub config: Option<HashMap<String, serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "is_zero", rename = "CreateIndex")]
+    pub create_index: u64,
+    #[serde(default, skip_serializing_if = "is_zero", rename = "ModifyIndex")]
+    pub modify_index: u64,
+}
+
+/// Credentials exchanged for a token by `Acl::login`
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct Login {
+    #[serde(rename = "AuthMethod")]
+    auth_method: String,
+    #[serde(rename = "BearerToken")]
+    bearer_token: String,
+    #[serde(skip_serializing_if = "HashMap::is_empty", rename = "Meta")]
+    meta: HashMap<String, String>,
+}
+
+impl Login {
+    /// Logs in and installs the token into `client` and its clones
+    pub(crate) fn perform(self, client: &Client) -> Box<dyn Future<Item = AclToken, Error = Error>> {
+        let body = match serde_json::to_vec(&self) {
+            Ok(body) => body,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        let client = client.clone();
+        // the token being replaced may be the reason for logging in again
+        let response = client.send_as(Method::Post, "/v1/acl/login", None, Some((ContentType::json(), body)));
+        Box::new(json_response::<AclToken>(response)
+        .and_then(|(token, _)| token.ok_or_else(|| Error::NotFound("ACL login returned 404".into())))
+        .map(move |token| {
+            client.set_token(Some(token.secret_id.clone()), Some(self));
+            token
+        }))
+    }
+}
+
+/// ACL endpoint
+pub struct Acl<'a> {
+    client: &'a Client,
+}
+
+impl<'a> Acl<'a> {
+    pub(crate) fn new(client: &'a Client) -> Self {
+        Acl { client }
+    }
+
+    /// Exchanges a bearer token, e.g. a Kubernetes service account JWT, for
+    /// a consul token through an auth method.
+    ///
+    /// The token is installed into the client and all its clones. Once
+    /// requests fail with `ACL not found` and the token turns out to be
+    /// gone, e.g. expired, the client logs in again with the same
+    /// credentials. Bearer tokens
+    /// which are rotated need another call to `login` to be picked up, the
+    /// token of the earlier login is then logged out.
+    pub fn login(&self, auth_method: &str, bearer_token: &str, meta: &HashMap<String, String>) -> Box<dyn Future<Item = AclToken, Error = Error>> {
+        let login = Login {
+            auth_method: auth_method.to_string(),
+            bearer_token: bearer_token.to_string(),
+            meta: meta.clone(),
+        };
+        let replaced = match self.client.auth.borrow().login {
+            Some(_) => self.client.token(),
+            None => None,
+        };
+        let client = self.client.clone();
+        Box::new(login.perform(self.client).and_then(move |token| -> Box<dyn Future<Item = _, Error = _>> {
+            match replaced {
+                // the new token works whether or not the old one could be destroyed
+                Some(replaced) => Box::new(client.send_as(Method::Post, "/v1/acl/logout", Some(replaced), None).then(|_| Ok(token))),
+                None => Box::new(future::ok(token)),
+            }
+        }))
+    }
+
+    /// Destroys the token obtained by `login` and stops sending it
+    pub fn logout(&self) -> Box<dyn Future<Item = (), Error = Error>> {
+        let client = self.client.clone();
+        let response = self.client.send_as(Method::Post, "/v1/acl/logout", self.client.token(), None);
+        Box::new(unit_response(response).map(move |()| client.set_token(None, None)))
+    }
+
+    /// Creates the initial management token, only works once per cluster
+    pub fn bootstrap(&self) -> Box<dyn Future<Item = AclToken, Error = Error>> {
+        Box::new(json_response(self.client.request_empty(Method::Put, "/v1/acl/bootstrap", None))
+        .and_then(|(token, _)| token.ok_or_else(|| Error::NotFound("ACL bootstrap returned 404".into()))))
+    }
+
+    /// Creates a token, the IDs are generated unless set
+    pub fn token_create(&self, token: &AclToken) -> Box<dyn Future<Item = AclToken, Error = Error>> {
+        self.token_create_with(token, &WriteOptions::default())
+    }
+
+    /// Same as `token_create`, with options such as a token of its own
+    pub fn token_create_with(&self, token: &AclToken, options: &WriteOptions) -> Box<dyn Future<Item = AclToken, Error = Error>> {
+        self.put("/v1/acl/token", token, options)
+    }
+
+    /// Fetches a token by accessor ID, returns `None` if it does not exist
+    pub fn token_read(&self, accessor_id: &str) -> Box<dyn Future<Item = Option<AclToken>, Error = Error>> {
+        self.token_read_with(accessor_id, &QueryOptions::default())
+    }
+
+    /// Same as `token_read`, with options such as a token of its own
+    pub fn token_read_with(&self, accessor_id: &str, options: &QueryOptions) -> Box<dyn Future<Item = Option<AclToken>, Error = Error>> {
+        let mut uri: String = "/v1/acl/token/".into();
+        uri.push_str(&escape::segment(accessor_id));
+        self.read(&uri, options)
+    }
+
+    /// Fetches the token the request is made with
+    pub fn token_self(&self) -> Box<dyn Future<Item = AclToken, Error = Error>> {
+        self.token_self_with(&QueryOptions::default())
+    }
+
+    /// Same as `token_self`, with options such as a token of its own
+    pub fn token_self_with(&self, options: &QueryOptions) -> Box<dyn Future<Item = AclToken, Error = Error>> {
+        Box::new(self.get("/v1/acl/token/self", options)
+        .and_then(|token| token.ok_or_else(|| Error::NotFound("token self returned 404".into()))))
+    }
+
+    /// Replaces the token with `token.accessor_id`
+    pub fn token_update(&self, token: &AclToken) -> Box<dyn Future<Item = AclToken, Error = Error>> {
+        self.token_update_with(token, &WriteOptions::default())
+    }
+
+    /// Same as `token_update`, with options such as a token of its own
+    pub fn token_update_with(&self, token: &AclToken, options: &WriteOptions) -> Box<dyn Future<Item = AclToken, Error = Error>> {
+        let mut uri: String = "/v1/acl/token/".into();
+        uri.push_str(&escape::segment(&token.accessor_id));
+        self.put(&uri, token, options)
+    }
+
+    /// Creates a token with the same permissions as an existing one
+    pub fn token_clone(&self, accessor_id: &str, description: &str) -> Box<dyn Future<Item = AclToken, Error = Error>> {
+        self.token_clone_with(accessor_id, description, &WriteOptions::default())
+    }
+
+    /// Same as `token_clone`, with options such as a token of its own
+    pub fn token_clone_with(&self, accessor_id: &str, description: &str, options: &WriteOptions) -> Box<dyn Future<Item = AclToken, Error = Error>> {
+        #[derive(Serialize)]
+        struct Clone<'a> {
+            #[serde(rename = "Description")]
+            description: &'a str,
+        }
+
+        let mut uri: String = "/v1/acl/token/".into();
+        uri.push_str(&escape::segment(accessor_id));
+        uri.push_str("/clone");
+        self.put(&uri, Clone { description }, options)
+    }
+
+    pub fn token_delete(&self, accessor_id: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        self.token_delete_with(accessor_id, &WriteOptions::default())
+    }
+
+    /// Same as `token_delete`, with options such as a token of its own
+    pub fn token_delete_with(&self, accessor_id: &str, options: &WriteOptions) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let mut uri: String = "/v1/acl/token/".into();
+        uri.push_str(&escape::segment(accessor_id));
+        self.delete(&uri, options)
+    }
+
+    /// Lists the tokens, without their secrets
+    pub fn tokens(&self) -> Box<dyn Future<Item = Vec<AclToken>, Error = Error>> {
+        self.tokens_with(&QueryOptions::default())
+    }
+
+    /// Same as `tokens`, with options such as a token of its own
+    pub fn tokens_with(&self, options: &QueryOptions) -> Box<dyn Future<Item = Vec<AclToken>, Error = Error>> {
+        self.list("/v1/acl/tokens", options)
+    }
+
+    pub fn policy_create(&self, policy: &AclPolicy) -> Box<dyn Future<Item = AclPolicy, Error = Error>> {
+        self.policy_create_with(policy, &WriteOptions::default())
+    }
+
+    /// Same as `policy_create`, with options such as a token of its own
+    pub fn policy_create_with(&self, policy: &AclPolicy, options: &WriteOptions) -> Box<dyn Future<Item = AclPolicy, Error = Error>> {
+        self.put("/v1/acl/policy", policy, options)
+    }
+
+    /// Fetches a policy by ID, returns `None` if it does not exist
+    pub fn policy_read(&self, id: &str) -> Box<dyn Future<Item = Option<AclPolicy>, Error = Error>> {
+        self.policy_read_with(id, &QueryOptions::default())
+    }
+
+    /// Same as `policy_read`, with options such as a token of its own
+    pub fn policy_read_with(&self, id: &str, options: &QueryOptions) -> Box<dyn Future<Item = Option<AclPolicy>, Error = Error>> {
+        let mut uri: String = "/v1/acl/policy/".into();
+        uri.push_str(&escape::segment(id));
+        self.read(&uri, options)
+    }
+
+    /// Fetches a policy by name, returns `None` if it does not exist
+    pub fn policy_read_by_name(&self, name: &str) -> Box<dyn Future<Item = Option<AclPolicy>, Error = Error>> {
+        self.policy_read_by_name_with(name, &QueryOptions::default())
+    }
+
+    /// Same as `policy_read_by_name`, with options such as a token of its own
+    pub fn policy_read_by_name_with(&self, name: &str, options: &QueryOptions) -> Box<dyn Future<Item = Option<AclPolicy>, Error = Error>> {
+        let mut uri: String = "/v1/acl/policy/name/".into();
+        uri.push_str(&escape::segment(name));
+        self.read(&uri, options)
+    }
+
+    /// Replaces the policy with `policy.id`
+    pub fn policy_update(&self, policy: &AclPolicy) -> Box<dyn Future<Item = AclPolicy, Error = Error>> {
+        self.policy_update_with(policy, &WriteOptions::default())
+    }
+
+    /// Same as `policy_update`, with options such as a token of its own
+    pub fn policy_update_with(&self, policy: &AclPolicy, options: &WriteOptions) -> Box<dyn Future<Item = AclPolicy, Error = Error>> {
+        let mut uri: String = "/v1/acl/policy/".into();
+        uri.push_str(&escape::segment(&policy.id));
+        self.put(&uri, policy, options)
+    }
+
+    pub fn policy_delete(&self, id: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        self.policy_delete_with(id, &WriteOptions::default())
+    }
+
+    /// Same as `policy_delete`, with options such as a token of its own
+    pub fn policy_delete_with(&self, id: &str, options: &WriteOptions) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let mut uri: String = "/v1/acl/policy/".into();
+        uri.push_str(&escape::segment(id));
+        self.delete(&uri, options)
+    }
+
+    /// Lists the policies, without their rules
+    pub fn policies(&self) -> Box<dyn Future<Item = Vec<AclPolicy>, Error = Error>> {
+        self.policies_with(&QueryOptions::default())
+    }
+
+    /// Same as `policies`, with options such as a token of its own
+    pub fn policies_with(&self, options: &QueryOptions) -> Box<dyn Future<Item = Vec<AclPolicy>, Error = Error>> {
+        self.list("/v1/acl/policies", options)
+    }
+
+    pub fn role_create(&self, role: &AclRole) -> Box<dyn Future<Item = AclRole, Error = Error>> {
+        self.role_create_with(role, &WriteOptions::default())
+    }
+
+    /// Same as `role_create`, with options such as a token of its own
+    pub fn role_create_with(&self, role: &AclRole, options: &WriteOptions) -> Box<dyn Future<Item = AclRole, Error = Error>> {
+        self.put("/v1/acl/role", role, options)
+    }
+
+    /// Fetches a role by ID, returns `None` if it does not exist
+    pub fn role_read(&self, id: &str) -> Box<dyn Future<Item = Option<AclRole>, Error = Error>> {
+        self.role_read_with(id, &QueryOptions::default())
+    }
+
+    /// Same as `role_read`, with options such as a token of its own
+    pub fn role_read_with(&self, id: &str, options: &QueryOptions) -> Box<dyn Future<Item = Option<AclRole>, Error = Error>> {
+        let mut uri: String = "/v1/acl/role/".into();
+        uri.push_str(&escape::segment(id));
+        self.read(&uri, options)
+    }
+
+    /// Fetches a role by name, returns `None` if it does not exist
+    pub fn role_read_by_name(&self, name: &str) -> Box<dyn Future<Item = Option<AclRole>, Error = Error>> {
+        self.role_read_by_name_with(name, &QueryOptions::default())
+    }
+
+    /// Same as `role_read_by_name`, with options such as a token of its own
+    pub fn role_read_by_name_with(&self, name: &str, options: &QueryOptions) -> Box<dyn Future<Item = Option<AclRole>, Error = Error>> {
+        let mut uri: String = "/v1/acl/role/name/".into();
+        uri.push_str(&escape::segment(name));
+        self.read(&uri, options)
+    }
+
+    /// Replaces the role with `role.id`
+    pub fn role_update(&self, role: &AclRole) -> Box<dyn Future<Item = AclRole, Error = Error>> {
+        self.role_update_with(role, &WriteOptions::default())
+    }
+
+    /// Same as `role_update`, with options such as a token of its own
+    pub fn role_update_with(&self, role: &AclRole, options: &WriteOptions) -> Box<dyn Future<Item = AclRole, Error = Error>> {
+        let mut uri: String = "/v1/acl/role/".into();
+        uri.push_str(&escape::segment(&role.id));
+        self.put(&uri, role, options)
+    }
+
+    pub fn role_delete(&self, id: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        self.role_delete_with(id, &WriteOptions::default())
+    }
+
+    /// Same as `role_delete`, with options such as a token of its own
+    pub fn role_delete_with(&self, id: &str, options: &WriteOptions) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let mut uri: String = "/v1/acl/role/".into();
+        uri.push_str(&escape::segment(id));
+        self.delete(&uri, options)
+    }
+
+    pub fn roles(&self) -> Box<dyn Future<Item = Vec<AclRole>, Error = Error>> {
+        self.roles_with(&QueryOptions::default())
+    }
+
+    /// Same as `roles`, with options such as a token of its own
+    pub fn roles_with(&self, options: &QueryOptions) -> Box<dyn Future<Item = Vec<AclRole>, Error = Error>> {
+        self.list("/v1/acl/roles", options)
+    }
+
+    pub fn binding_rule_create(&self, rule: &AclBindingRule) -> Box<dyn Future<Item = AclBindingRule, Error = Error>> {
+        self.binding_rule_create_with(rule, &WriteOptions::default())
+    }
+
+    /// Same as `binding_rule_create`, with options such as a token of its own
+    pub fn binding_rule_create_with(&self, rule: &AclBindingRule, options: &WriteOptions) -> Box<dyn Future<Item = AclBindingRule, Error = Error>> {
+        self.put("/v1/acl/binding-rule", rule, options)
+    }
+
+    /// Fetches a binding rule, returns `None` if it does not exist
+    pub fn binding_rule_read(&self, id: &str) -> Box<dyn Future<Item = Option<AclBindingRule>, Error = Error>> {
+        self.binding_rule_read_with(id, &QueryOptions::default())
+    }
+
+    /// Same as `binding_rule_read`, with options such as a token of its own
+    pub fn binding_rule_read_with(&self, id: &str, options: &QueryOptions) -> Box<dyn Future<Item = Option<AclBindingRule>, Error = Error>> {
+        let mut uri: String = "/v1/acl/binding-rule/".into();
+        uri.push_str(&escape::segment(id));
+        self.read(&uri, options)
+    }
+
+    /// Replaces the binding rule with `rule.id`
+    pub fn binding_rule_update(&self, rule: &AclBindingRule) -> Box<dyn Future<Item = AclBindingRule, Error = Error>> {
+        self.binding_rule_update_with(rule, &WriteOptions::default())
+    }
+
+    /// Same as `binding_rule_update`, with options such as a token of its own
+    pub fn binding_rule_update_with(&self, rule: &AclBindingRule, options: &WriteOptions) -> Box<dyn Future<Item = AclBindingRule, Error = Error>> {
+        let mut uri: String = "/v1/acl/binding-rule/".into();
+        uri.push_str(&escape::segment(&rule.id));
+        self.put(&uri, rule, options)
+    }
+
+    pub fn binding_rule_delete(&self, id: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        self.binding_rule_delete_with(id, &WriteOptions::default())
+    }
+
+    /// Same as `binding_rule_delete`, with options such as a token of its own
+    pub fn binding_rule_delete_with(&self, id: &str, options: &WriteOptions) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let mut uri: String = "/v1/acl/binding-rule/".into();
+        uri.push_str(&escape::segment(id));
+        self.delete(&uri, options)
+    }
+
+    /// Lists the binding rules, optionally only the ones of an auth method
+    pub fn binding_rules(&self, auth_method: Option<&str>) -> Box<dyn Future<Item = Vec<AclBindingRule>, Error = Error>> {
+        self.binding_rules_with(auth_method, &QueryOptions::default())
+    }
+
+    /// Same as `binding_rules`, with options such as a token of its own
+    pub fn binding_rules_with(&self, auth_method: Option<&str>, options: &QueryOptions) -> Box<dyn Future<Item = Vec<AclBindingRule>, Error = Error>> {
+        let mut uri: String = "/v1/acl/binding-rules".into();
+        if let Some(auth_method) = auth_method {
+            append_query(&mut uri, vec![format!("authmethod={}", escape::query(auth_method))]);
+        }
+        self.list(&uri, options)
+    }
+
+    pub fn auth_method_create(&self, method: &AclAuthMethod) -> Box<dyn Future<Item = AclAuthMethod, Error = Error>> {
+        self.auth_method_create_with(method, &WriteOptions::default())
+    }
+
+    /// Same as `auth_method_create`, with options such as a token of its own
+    pub fn auth_method_create_with(&self, method: &AclAuthMethod, options: &WriteOptions) -> Box<dyn Future<Item = AclAuthMethod, Error = Error>> {
+        self.put("/v1/acl/auth-method", method, options)
+    }
+
+    /// Fetches an auth method, returns `None` if it does not exist
+    pub fn auth_method_read(&self, name: &str) -> Box<dyn Future<Item = Option<AclAuthMethod>, Error = Error>> {
+        self.auth_method_read_with(name, &QueryOptions::default())
+    }
+
+    /// Same as `auth_method_read`, with options such as a token of its own
+    pub fn auth_method_read_with(&self, name: &str, options: &QueryOptions) -> Box<dyn Future<Item = Option<AclAuthMethod>, Error = Error>> {
+        let mut uri: String = "/v1/acl/auth-method/".into();
+        uri.push_str(&escape::segment(name));
+        self.read(&uri, options)
+    }
+
+    /// Replaces the auth method with `method.name`
+    pub fn auth_method_update(&self, method: &AclAuthMethod) -> Box<dyn Future<Item = AclAuthMethod, Error = Error>> {
+        self.auth_method_update_with(method, &WriteOptions::default())
+    }
+
+    /// Same as `auth_method_update`, with options such as a token of its own
+    pub fn auth_method_update_with(&self, method: &AclAuthMethod, options: &WriteOptions) -> Box<dyn Future<Item = AclAuthMethod, Error = Error>> {
+        let mut uri: String = "/v1/acl/auth-method/".into();
+        uri.push_str(&escape::segment(&method.name));
+        self.put(&uri, method, options)
+    }
+
+    pub fn auth_method_delete(&self, name: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        self.auth_method_delete_with(name, &WriteOptions::default())
+    }
+
+    /// Same as `auth_method_delete`, with options such as a token of its own
+    pub fn auth_method_delete_with(&self, name: &str, options: &WriteOptions) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let mut uri: String = "/v1/acl/auth-method/".into();
+        uri.push_str(&escape::segment(name));
+        self.delete(&uri, options)
+    }
+
+    /// Lists the auth methods, without their config
+    pub fn auth_methods(&self) -> Box<dyn Future<Item = Vec<AclAuthMethod>, Error = Error>> {
+        self.auth_methods_with(&QueryOptions::default())
+    }
+
+    /// Same as `auth_methods`, with options such as a token of its own
+    pub fn auth_methods_with(&self, options: &QueryOptions) -> Box<dyn Future<Item = Vec<AclAuthMethod>, Error = Error>> {
+        self.list("/v1/acl/auth-methods", options)
+    }
+
+    fn get<T: DeserializeOwned + 'static>(&self, path: &str, options: &QueryOptions) -> Box<dyn Future<Item = Option<T>, Error = Error>> {
+        Box::new(self.client.get_json(path, options).map(|(value, _)| value))
+    }
+
+    /// Fetches a single object. Consul answers `ACL not found` both when the
+    /// object does not exist and when the token is unknown, so the token is
+    /// looked up to tell the two apart unless sending already did.
+    fn read<T: DeserializeOwned + 'static>(&self, path: &str, options: &QueryOptions) -> Box<dyn Future<Item = Option<T>, Error = Error>> {
+        let mut uri = path.to_string();
+        append_query(&mut uri, options.params());
+        let client = self.client.clone();
+        let token = QueryOptions { token: options.token.clone(), ..Default::default() };
+        Box::new(self.client.send_checked(Method::Get, &uri, options.token.as_deref(), None)
+        .and_then(move |(resp, token_valid)| {
+            json_response(Box::new(future::ok(resp))).map(|(value, _)| value)
+            .or_else(move |e| -> Box<dyn Future<Item = _, Error = _>> {
+                let missing = match e {
+                    Error::PermissionDenied(ref refusal) => refusal.contains("ACL not found"),
+                    _ => false,
+                };
+                if !missing {
+                    return Box::new(future::err(e));
+                }
+                if token_valid {
+                    return Box::new(future::ok(None));
+                }
+                Box::new(client.acl().token_self_with(&token).then(move |found| match found {
+                    Ok(_) => Ok(None),
+                    Err(_) => Err(e),
+                }))
+            })
+        }))
+    }
+
+    fn list<T: DeserializeOwned + 'static>(&self, path: &str, options: &QueryOptions) -> Box<dyn Future<Item = Vec<T>, Error = Error>> {
+        Box::new(self.get(path, options).map(|values| values.unwrap_or_default()))
+    }
+
+    /// PUTs `body` and decodes the answer, which is the object as stored
+    fn put<T: DeserializeOwned + 'static, B: Serialize>(&self, path: &str, body: B, options: &WriteOptions) -> Box<dyn Future<Item = T, Error = Error>> {
+        let path = path.to_string();
+        Box::new(json_response(self.client.request_json(Method::Put, &path, options.token.as_deref(), body))
+        .and_then(move |(value, _)| value.ok_or_else(|| Error::NotFound(format!("{} returned 404", path)))))
+    }
+
+    fn delete(&self, path: &str, options: &WriteOptions) -> Box<dyn Future<Item = bool, Error = Error>> {
+        bool_response(self.client.request_empty(Method::Delete, path, options.token.as_deref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use futures::Future;
+    use serde_json;
+    use tokio_core::reactor::Core;
+
+    use mock::{self, Reply};
+    use {AclBindingRule, AclLink, AclServiceIdentity, AclToken, BindType, Client, Error};
+
+    #[test]
+    fn tokens() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"AccessorID":"6a1253d2","SecretID":"45a3bd52","Description":"web deployer",
+                                "Policies":[{"ID":"165d4317","Name":"web-write"}],
+                                "ServiceIdentities":[{"ServiceName":"web"}],"Local":false,
+                                "CreateTime":"2019-04-25T16:04:37.36Z","Hash":"UuiRkOQPRCvoRZHRtUxxbrmwZ5crYrOdZ0Z1FTFbTbA=",
+                                "CreateIndex":59,"ModifyIndex":59}"#),
+            Reply::new(403, "ACL not found"),
+            Reply::new(200, r#"{"AccessorID":"b8f4a3c1","Local":false}"#),
+            Reply::new(403, "ACL not found"),
+            Reply::new(403, "ACL not found"),
+            Reply::new(200, r#"[{"AccessorID":"6a1253d2","Description":"web deployer","Local":false,"CreateIndex":59,"ModifyIndex":59}]"#),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap().with_token("management");
+
+        let token = AclToken {
+            description: "web deployer".into(),
+            policies: Some(vec![AclLink::name("web-write")]),
+            service_identities: Some(vec![AclServiceIdentity { service_name: "web".into(), ..Default::default() }]),
+            ..Default::default()
+        };
+        let created = core.run(client.acl().token_create(&token)).unwrap();
+        let req = requests.recv().unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("PUT", "/v1/acl/token"));
+        assert_eq!(req.header("X-Consul-Token"), Some("management"));
+        assert_eq!(String::from_utf8(req.body).unwrap(),
+                   r#"{"Description":"web deployer","Policies":[{"Name":"web-write"}],"ServiceIdentities":[{"ServiceName":"web"}],"Local":false}"#);
+        assert_eq!(created.secret_id, "45a3bd52");
+        assert_eq!(created.policies.unwrap()[0].id, "165d4317");
+
+        // the token is valid, so the refusal means the object is missing
+        assert!(core.run(client.acl().token_read("missing")).unwrap().is_none());
+        let paths: Vec<_> = requests.iter().take(2).map(|req| req.path).collect();
+        assert_eq!(paths, vec!["/v1/acl/token/missing", "/v1/acl/token/self"]);
+
+        match core.run(client.acl().policy_read("165d4317")) {
+            Err(Error::PermissionDenied(ref body)) if body == "ACL not found" => {}
+            other => panic!("expected permission denied, got {:?}", other),
+        }
+        let paths: Vec<_> = requests.iter().take(2).map(|req| req.path).collect();
+        assert_eq!(paths, vec!["/v1/acl/policy/165d4317", "/v1/acl/token/self"]);
+
+        let tokens = core.run(client.acl().tokens()).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/acl/tokens");
+        assert_eq!(tokens[0].accessor_id, "6a1253d2");
+        assert!(tokens[0].secret_id.is_empty());
+
+        assert!(core.run(client.acl().token_delete("6a1253d2")).unwrap());
+        let req = requests.recv().unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("DELETE", "/v1/acl/token/6a1253d2"));
+    }
+
+    #[test]
+    fn login_relogin_and_logout() {
+        let token = |secret: &str| format!(r#"{{"AccessorID":"a-{0}","SecretID":"{0}","AuthMethod":"minikube","Local":true}}"#, secret);
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, &token("t1")),
+            Reply::new(403, "ACL not found"),
+            Reply::new(403, "ACL not found"),
+            Reply::new(200, &token("t2")),
+            Reply::new(200, "{}"),
+            Reply::new(403, "Permission denied"),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+        let clone = client.clone();
+
+        let mut meta = HashMap::new();
+        meta.insert("pod".to_string(), "web-0".to_string());
+        let logged_in = core.run(client.acl().login("minikube", "eyJhbGciOi", &meta)).unwrap();
+        let req = requests.recv().unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("POST", "/v1/acl/login"));
+        assert_eq!(req.header("X-Consul-Token"), None);
+        assert_eq!(String::from_utf8(req.body).unwrap(), r#"{"AuthMethod":"minikube","BearerToken":"eyJhbGciOi","Meta":{"pod":"web-0"}}"#);
+        assert_eq!(logged_in.secret_id, "t1");
+        assert_eq!(clone.token().as_deref(), Some("t1"));
+
+        // the token expired: log in again and repeat the request
+        core.run(clone.agent().services()).unwrap();
+        let tokens: Vec<_> = requests.iter().take(4)
+            .map(|req| (req.path.clone(), req.header("X-Consul-Token").map(|token| token.to_string())))
+            .collect();
+        assert_eq!(tokens, vec![
+            ("/v1/agent/services".to_string(), Some("t1".to_string())),
+            ("/v1/acl/token/self".to_string(), Some("t1".to_string())),
+            ("/v1/acl/login".to_string(), None),
+            ("/v1/agent/services".to_string(), Some("t2".to_string())),
+        ]);
+
+        // other refusals come through as they are
+        match core.run(client.agent().services()) {
+            Err(Error::PermissionDenied(ref body)) if body == "Permission denied" => {}
+            other => panic!("expected permission denied, got {:?}", other),
+        }
+        requests.recv().unwrap();
+
+        core.run(client.acl().logout()).unwrap();
+        let req = requests.recv().unwrap();
+        assert_eq!((req.path.as_str(), req.header("X-Consul-Token")), ("/v1/acl/logout", Some("t2")));
+        assert_eq!(clone.token(), None);
+    }
+
+    #[test]
+    fn relogin_checks_the_token_once() {
+        let token = |secret: &str| format!(r#"{{"AccessorID":"a-{0}","SecretID":"{0}","AuthMethod":"minikube","Local":true}}"#, secret);
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, &token("t1")),
+            Reply::new(403, "ACL not found"),
+            Reply::new(200, &token("t1")),
+            Reply::new(403, "ACL not found"),
+            Reply::new(403, "ACL not found"),
+            Reply::new(403, "ACL not found"),
+            Reply::new(200, &token("t2")),
+            Reply::new(200, "{}"),
+            Reply::new(200, "{}"),
+            Reply::new(200, &token("t3")),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+        let tokens = |count| -> Vec<(String, Option<String>)> {
+            requests.iter().take(count)
+                .map(|req| (req.path.clone(), req.header("X-Consul-Token").map(|token| token.to_string())))
+                .collect()
+        };
+        let entry = |path: &str, token: Option<&str>| (path.to_string(), token.map(|token| token.to_string()));
+
+        core.run(client.acl().login("minikube", "eyJhbGciOi", &HashMap::new())).unwrap();
+        tokens(1);
+
+        // the token is still valid, the policy is what is missing
+        assert!(core.run(client.acl().policy_read("165d4317")).unwrap().is_none());
+        assert_eq!(tokens(2), vec![
+            entry("/v1/acl/policy/165d4317", Some("t1")),
+            entry("/v1/acl/token/self", Some("t1")),
+        ]);
+        assert_eq!(client.token().as_deref(), Some("t1"));
+
+        // requests refused together share the check and the login
+        core.run(client.agent().services().join(client.agent().services())).unwrap();
+        assert_eq!(tokens(6), vec![
+            entry("/v1/agent/services", Some("t1")),
+            entry("/v1/agent/services", Some("t1")),
+            entry("/v1/acl/token/self", Some("t1")),
+            entry("/v1/acl/login", None),
+            entry("/v1/agent/services", Some("t2")),
+            entry("/v1/agent/services", Some("t2")),
+        ]);
+
+        // logging in again gets rid of the token it replaces
+        core.run(client.acl().login("minikube", "eyJhbGciOi", &HashMap::new())).unwrap();
+        assert_eq!(tokens(2), vec![
+            entry("/v1/acl/login", None),
+            entry("/v1/acl/logout", Some("t2")),
+        ]);
+        assert_eq!(client.token().as_deref(), Some("t3"));
+    }
+
+    #[test]
+    fn binding_rules() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"000ed53c","Description":"","AuthMethod":"minikube","Selector":"serviceaccount.namespace==default",
+                                "BindType":"service","BindName":"{{ serviceaccount.name }}","CreateIndex":17,"ModifyIndex":17}"#),
+            Reply::new(200, "[]"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let rule = AclBindingRule {
+            auth_method: "minikube".into(),
+            selector: "serviceaccount.namespace==default".into(),
+            bind_type: BindType::Service,
+            bind_name: "{{ serviceaccount.name }}".into(),
+            ..Default::default()
+        };
+        let created = core.run(client.acl().binding_rule_create(&rule)).unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&requests.recv().unwrap().body).unwrap();
+        assert_eq!(body["BindType"], "service");
+        assert_eq!(created.id, "000ed53c");
+
+        core.run(client.acl().binding_rules(Some("minikube"))).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/acl/binding-rules?authmethod=minikube");
+    }
+}
@@ -0,0 +1,187 @@
+//! Catalog endpoint
+
+use std::collections::HashMap;
+
+use futures::Future;
+
+use {escape, Client, Error, Node, QueryMeta, QueryOptions, Service, Watch};
+
+/// Service names mapped to the tags in use by their instances
+type Services = HashMap<String, Vec<String>>;
+
+/// CatalogService is a service instance as registered in the catalog
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CatalogService {
+    #[serde(default, rename = "ID")]
+    pub id: String,
+    #[serde(rename = "Node")]
+    pub node: String,
+    #[serde(rename = "Address")]
+    pub address: String,
+    #[serde(default, rename = "Datacenter")]
+    pub datacenter: String,
+    #[serde(default, rename = "TaggedAddresses")]
+    pub tagged_addresses: Option<HashMap<String, String>>,
+    #[serde(default, rename = "NodeMeta")]
+    pub node_meta: Option<HashMap<String, String>>,
+    #[serde(rename = "ServiceID")]
+    pub service_id: String,
+    #[serde(rename = "ServiceName")]
+    pub service_name: String,
+    #[serde(default, rename = "ServiceAddress")]
+    pub service_address: String,
+    #[serde(default, rename = "ServiceTags")]
+    pub service_tags: Option<Vec<String>>,
+    #[serde(default, rename = "ServiceMeta")]
+    pub service_meta: Option<HashMap<String, String>>,
+    #[serde(rename = "ServicePort")]
+    pub service_port: u32,
+    #[serde(default, rename = "CreateIndex")]
+    pub create_index: u64,
+    #[serde(default, rename = "ModifyIndex")]
+    pub modify_index: u64,
+}
+
+/// CatalogNode is a node along with the services registered on it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CatalogNode {
+    #[serde(rename = "Node")]
+    pub node: Node,
+    #[serde(rename = "Services")]
+    pub services: HashMap<String, Service>,
+}
+
+/// Catalog endpoint
+pub struct Catalog<'a> {
+    client: &'a Client,
+}
+
+impl<'a> Catalog<'a> {
+    pub(crate) fn new(client: &'a Client) -> Self {
+        Catalog { client }
+    }
+
+    /// Lists all the known datacenters
+    pub fn datacenters(&self) -> Box<dyn Future<Item = Vec<String>, Error = Error>> {
+        Box::new(self.client.get_json("/v1/catalog/datacenters", &QueryOptions::default())
+        .map(|(dcs, _)| dcs.unwrap_or_default()))
+    }
+
+    /// Lists all the nodes
+    pub fn nodes(&self) -> Box<dyn Future<Item = Vec<Node>, Error = Error>> {
+        Box::new(self.nodes_with(&QueryOptions::default()).map(|(nodes, _)| nodes))
+    }
+
+    /// Same as `nodes`, but allows blocking queries
+    pub fn nodes_with(&self, options: &QueryOptions) -> Box<dyn Future<Item = (Vec<Node>, QueryMeta), Error = Error>> {
+        Box::new(self.client.get_json("/v1/catalog/nodes", options)
+        .map(|(nodes, meta)| (nodes.unwrap_or_default(), meta)))
+    }
+
+    /// Lists the names of all the services along with their tags
+    pub fn services(&self) -> Box<dyn Future<Item = Services, Error = Error>> {
+        Box::new(self.services_with(&QueryOptions::default()).map(|(services, _)| services))
+    }
+
+    /// Same as `services`, but allows blocking queries
+    pub fn services_with(&self, options: &QueryOptions) -> Box<dyn Future<Item = (Services, QueryMeta), Error = Error>> {
+        Box::new(self.client.get_json("/v1/catalog/services", options)
+        .map(|(services, meta)| (services.unwrap_or_default(), meta)))
+    }
+
+    /// Lists the instances of a service, optionally only those having `tag`
+    pub fn service(&self, name: &str, tag: Option<&str>) -> Box<dyn Future<Item = Vec<CatalogService>, Error = Error>> {
+        Box::new(self.service_with(name, tag, &QueryOptions::default()).map(|(services, _)| services))
+    }
+
+    /// Same as `service`, but allows blocking queries
+    pub fn service_with(&self, name: &str, tag: Option<&str>, options: &QueryOptions) -> Box<dyn Future<Item = (Vec<CatalogService>, QueryMeta), Error = Error>> {
+        let mut uri: String = "/v1/catalog/service/".into();
+        uri.push_str(&escape::segment(name));
+        if let Some(tag) = tag {
+            uri.push_str("?tag=");
+            uri.push_str(&escape::query(tag));
+        }
+        Box::new(self.client.get_json(&uri, options)
+        .map(|(services, meta)| (services.unwrap_or_default(), meta)))
+    }
+
+    /// Fetches a node and the services registered on it, returns `None` if
+    /// the node is unknown
+    pub fn node(&self, name: &str) -> Box<dyn Future<Item = Option<CatalogNode>, Error = Error>> {
+        Box::new(self.node_with(name, &QueryOptions::default()).map(|(node, _)| node))
+    }
+
+    /// Same as `node`, but allows blocking queries
+    pub fn node_with(&self, name: &str, options: &QueryOptions) -> Box<dyn Future<Item = (Option<CatalogNode>, QueryMeta), Error = Error>> {
+        let mut uri: String = "/v1/catalog/node/".into();
+        uri.push_str(&escape::segment(name));
+        // unknown nodes come back as `null` rather than 404
+        Box::new(self.client.get_json::<Option<CatalogNode>>(&uri, options)
+        .map(|(node, meta)| (node.and_then(|node| node), meta)))
+    }
+
+    /// Watches the list of services
+    pub fn watch_services(&self, options: &QueryOptions) -> Watch<Services> {
+        let client = self.client.clone();
+        Watch::new(self.client.handle(), options.clone(), move |options| client.catalog().services_with(options))
+    }
+
+    /// Watches the instances of a service
+    pub fn watch_service(&self, name: &str, tag: Option<&str>, options: &QueryOptions) -> Watch<Vec<CatalogService>> {
+        let client = self.client.clone();
+        let name = name.to_string();
+        let tag = tag.map(|tag| tag.to_string());
+        Watch::new(self.client.handle(), options.clone(), move |options| {
+            client.catalog().service_with(&name, tag.as_deref(), options)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::Core;
+
+    use mock::{self, Reply};
+    use Client;
+
+    #[test]
+    fn catalog() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"["dc1","dc2"]"#),
+            Reply::new(200, r#"{"consul":[],"redis":["primary","v1"]}"#),
+            Reply::new(200, r#"[{"ID":"40e4a748","Node":"foobar","Address":"192.168.10.10","Datacenter":"dc1",
+                                "TaggedAddresses":{"lan":"192.168.10.10","wan":"10.0.10.10"},"NodeMeta":{"somekey":"somevalue"},
+                                "ServiceID":"redis1","ServiceName":"redis","ServiceAddress":"172.17.0.3","ServiceTags":["primary"],
+                                "ServiceMeta":{"redis_version":"4.0"},"ServicePort":8000,"CreateIndex":5,"ModifyIndex":6}]"#),
+            Reply::new(200, r#"{"Node":{"ID":"40e4a748","Node":"foobar","Address":"10.1.10.12","Datacenter":"dc1",
+                                "TaggedAddresses":{"lan":"10.1.10.12"},"Meta":{"instance_type":"t2.medium"},"CreateIndex":3,"ModifyIndex":4},
+                                "Services":{"redis1":{"ID":"redis1","Service":"redis","Tags":["v1"],"Address":"","Port":8000}}}"#),
+            Reply::new(200, "null"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let dcs = core.run(client.catalog().datacenters()).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/catalog/datacenters");
+        assert_eq!(dcs, vec!["dc1".to_string(), "dc2".to_string()]);
+
+        let services = core.run(client.catalog().services()).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/catalog/services");
+        assert_eq!(services["redis"], vec!["primary".to_string(), "v1".to_string()]);
+
+        let instances = core.run(client.catalog().service("redis", Some("primary"))).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/catalog/service/redis?tag=primary");
+        assert_eq!(instances[0].service_address, "172.17.0.3");
+        assert_eq!(instances[0].service_port, 8000);
+        assert_eq!(instances[0].tagged_addresses.as_ref().unwrap()["wan"], "10.0.10.10");
+
+        let node = core.run(client.catalog().node("foobar")).unwrap().unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/catalog/node/foobar");
+        assert_eq!(node.node.datacenter, "dc1");
+        assert_eq!(node.node.meta.as_ref().unwrap()["instance_type"], "t2.medium");
+        assert_eq!(node.services["redis1"].port, 8000);
+
+        assert!(core.run(client.catalog().node("missing")).unwrap().is_none());
+    }
+}
@@ -0,0 +1,178 @@
+//! Connections to the agent, over plain TCP, TLS or a Unix socket
+
+use std::io::{self, Read, Write};
+
+#[cfg(not(feature = "tls"))]
+use futures::future;
+use futures::{Future, IntoFuture, Poll};
+use hyper::Uri;
+use hyper::client::{HttpConnector, Service};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "tls")]
+use native_tls;
+#[cfg(feature = "tls")]
+use tokio_tls::{TlsConnector, TlsStream};
+#[cfg(unix)]
+use tokio_uds::UnixStream;
+
+use Error;
+
+/// How to set up TLS for `https` agents, files are PEM encoded
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub(crate) struct TlsConfig {
+    /// CA bundle to verify the agent with instead of the system roots
+    pub ca_cert: Option<String>,
+    /// Client certificate and its PKCS#8 key
+    pub client_cert: Option<(String, String)>,
+    /// Name to send as SNI and to verify the certificate against
+    pub server_name: Option<String>,
+    pub insecure_skip_verify: bool,
+}
+
+/// Connector handing hyper plain or TLS streams depending on the scheme,
+/// or connections to a Unix socket whatever the URI
+pub(crate) struct Connector {
+    http: HttpConnector,
+    #[cfg(feature = "tls")]
+    tls: TlsConnector,
+    server_name: Option<String>,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    socket: Option<String>,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    handle: Handle,
+}
+
+impl Connector {
+    /// Creates a connector for TCP, or for the Unix socket at `socket`
+    pub fn new(handle: &Handle, socket: Option<String>, config: &TlsConfig) -> Result<Self, Error> {
+        if cfg!(not(unix)) && socket.is_some() {
+            return Err(io::Error::other("unix sockets are not supported on this platform").into());
+        }
+        let mut http = HttpConnector::new(4, handle);
+        http.enforce_http(false);
+        Ok(Connector {
+            http,
+            #[cfg(feature = "tls")]
+            tls: tls_connector(config)?,
+            server_name: config.server_name.clone(),
+            socket,
+            handle: handle.clone(),
+        })
+    }
+}
+
+#[cfg(feature = "tls")]
+fn tls_connector(config: &TlsConfig) -> Result<TlsConnector, Error> {
+    use std::fs;
+
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(ref path) = config.ca_cert {
+        for cert in native_tls::Certificate::stack_from_pem(&fs::read(path)?)? {
+            builder.add_root_certificate(cert);
+        }
+    }
+    if let Some((ref cert, ref key)) = config.client_cert {
+        builder.identity(native_tls::Identity::from_pkcs8(&fs::read(cert)?, &fs::read(key)?)?);
+    }
+    builder.danger_accept_invalid_certs(config.insecure_skip_verify);
+    builder.danger_accept_invalid_hostnames(config.insecure_skip_verify);
+    Ok(builder.build()?.into())
+}
+
+impl Service for Connector {
+    type Request = Uri;
+    type Response = Stream;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Stream, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        #[cfg(unix)]
+        {
+            if let Some(ref socket) = self.socket {
+                return Box::new(UnixStream::connect(socket, &self.handle).map(Stream::Unix).into_future());
+            }
+        }
+        if uri.scheme() != Some("https") {
+            return Box::new(self.http.call(uri).map(Stream::Plain));
+        }
+        let host = self.server_name.clone().or_else(|| uri.host().map(|host| host.to_string())).unwrap_or_default();
+        self.call_tls(uri, host)
+    }
+}
+
+impl Connector {
+    #[cfg(feature = "tls")]
+    fn call_tls(&self, uri: Uri, host: String) -> Box<dyn Future<Item = Stream, Error = io::Error>> {
+        let tls = self.tls.clone();
+        Box::new(self.http.call(uri).and_then(move |tcp| {
+            tls.connect(&host, tcp)
+            .map(|stream| Stream::Tls(Box::new(stream)))
+            .map_err(io::Error::other)
+        }))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn call_tls(&self, _uri: Uri, _host: String) -> Box<dyn Future<Item = Stream, Error = io::Error>> {
+        Box::new(future::err(io::Error::other("https needs the `tls` feature")))
+    }
+}
+
+/// Connection to the agent
+pub(crate) enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Stream {}
+
+impl AsyncWrite for Stream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            Stream::Plain(ref mut stream) => AsyncWrite::shutdown(stream),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.shutdown(),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => AsyncWrite::shutdown(stream),
+        }
+    }
+}
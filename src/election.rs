@@ -0,0 +1,345 @@
+//! Leader election on top of the lock layout
+
+use std::time::Duration;
+
+use futures::{future, Async, Future, IntoFuture, Poll, Stream};
+use futures::future::{Either, Loop};
+use futures::sync::{mpsc, oneshot};
+use tokio_core::reactor::Timeout;
+
+use {Client, CreateSession, Error, LockOptions, PutOptions, QueryOptions, SessionBehavior, SessionKeeper, LOCK_FLAG_VALUE};
+
+/// Role of this process in an election
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Leadership {
+    /// This process holds the key, `value` is what it stored there
+    Leader { value: Vec<u8> },
+    /// Somebody else leads, `leader` is the value they stored in the key or
+    /// `None` while nobody holds it
+    Follower { leader: Option<Vec<u8>> },
+}
+
+/// Contends for leadership using the same key layout as `Lock`, so a Go
+/// process using `api.Lock` on the key takes part in the same election
+pub struct LeaderElection {
+    client: Client,
+    options: LockOptions,
+}
+
+impl LeaderElection {
+    pub fn new(client: &Client, options: LockOptions) -> Self {
+        LeaderElection { client: client.clone(), options }
+    }
+
+    /// Starts contending on the client's reactor.
+    ///
+    /// When the session is lost or consul cannot be queried, a new session
+    /// is created after `retry_delay` and the process contends again.
+    /// Dropping the returned stream steps down and destroys the session.
+    pub fn campaign(&self) -> Election {
+        let (changes_tx, changes) = mpsc::unbounded();
+        let (stop, stopped) = oneshot::channel::<()>();
+
+        let client = self.client.clone();
+        let options = self.options.clone();
+        let terms = future::loop_fn((), move |()| {
+            let client = client.clone();
+            let retry_delay = options.retry_delay;
+            let changes = changes_tx.clone();
+            term(&client, &options, &changes_tx).then(move |result| -> Box<dyn Future<Item = _, Error = _>> {
+                if let Err(e @ Error::Conflict(_)) = result {
+                    // the key is used by something else, trying again won't help
+                    let _ = changes.unbounded_send(Err(e));
+                    return Box::new(future::ok(Loop::Break(())));
+                }
+                Box::new(Timeout::new(retry_delay, client.handle()).into_future().flatten()
+                .map(|()| Loop::Continue(()))
+                .map_err(|_| ()))
+            })
+        });
+        let task = terms.select(stopped.then(|_| Ok(()))).then(|_| Ok(()));
+        self.client.handle().spawn(task);
+
+        Election { changes, last: None, _stop: stop }
+    }
+}
+
+/// Longest delay between two attempts at a failed query
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Contends with one session until it is lost, then reports that this
+/// process is no longer the leader.
+///
+/// Failed queries are retried after a delay growing from `retry_delay`, up
+/// to `monitor_retries` times in a row. After that the term ends as well:
+/// consul may have expired the session meanwhile and let another process
+/// take the key.
+fn term(client: &Client, options: &LockOptions, changes: &mpsc::UnboundedSender<Result<Leadership, Error>>) -> Box<dyn Future<Item = (), Error = Error>> {
+    let client = client.clone();
+    let options = options.clone();
+    let changes = changes.clone();
+    let ended = changes.clone();
+    let session = CreateSession {
+        name: Some(options.session_name.clone()),
+        ttl: Some(options.session_ttl.clone()),
+        behavior: Some(SessionBehavior::Release),
+        ..Default::default()
+    };
+    Box::new(SessionKeeper::create(&client, &session).and_then(move |keeper| {
+        let session = keeper.id().to_string();
+        let lost = keeper.lost();
+        let retry_delay = options.retry_delay;
+        let retries = options.monitor_retries;
+        let observed = future::loop_fn((None, retry_delay, 0), move |(index, backoff, failures)| {
+            let client = client.clone();
+            observe(&client, &options, &session, &changes, index).then(move |result| -> Box<dyn Future<Item = _, Error = _>> {
+                let index = match result {
+                    Ok(Loop::Continue(index)) => return Box::new(future::ok(Loop::Continue((index, retry_delay, 0)))),
+                    Ok(Loop::Break(())) => return Box::new(future::ok(Loop::Break(()))),
+                    Err(e @ Error::Conflict(_)) => return Box::new(future::err(e)),
+                    Err(_) if failures < retries => index,
+                    Err(e) => return Box::new(future::err(e)),
+                };
+                let retry = Loop::Continue((index, ::std::cmp::min(backoff * 2, MAX_BACKOFF), failures + 1));
+                match Timeout::new(backoff, client.handle()) {
+                    Ok(timeout) => Box::new(timeout.then(|_| Ok(retry))),
+                    Err(_) => Box::new(future::ok(retry)),
+                }
+            })
+        });
+        observed.select2(lost).then(move |result| {
+            drop(keeper);
+            match result {
+                Err(Either::A((e, _))) => Err(e),
+                _ => Ok(()),
+            }
+        })
+    })
+    .then(move |result| {
+        let _ = ended.unbounded_send(Ok(Leadership::Follower { leader: None }));
+        result
+    }))
+}
+
+/// Reports who holds the key and tries to take it when nobody does,
+/// continues with the index to block on
+fn observe(client: &Client, options: &LockOptions, session: &str, changes: &mpsc::UnboundedSender<Result<Leadership, Error>>, index: Option<u64>) -> Box<dyn Future<Item = Loop<(), Option<u64>>, Error = Error>> {
+    let query = QueryOptions { index, wait: Some(options.lock_wait), ..Default::default() };
+    let client = client.clone();
+    let key = options.key.clone();
+    let value = options.value.clone();
+    let retry_delay = options.retry_delay;
+    let session = session.to_string();
+    let changes = changes.clone();
+    Box::new(client.kv().get_with(&key, &query).and_then(move |(pair, meta)| -> Box<dyn Future<Item = _, Error = _>> {
+        if let Some(pair) = pair {
+            if pair.flags != LOCK_FLAG_VALUE {
+                return Box::new(future::err(Error::Conflict(format!("{} is not used as a lock", key))));
+            }
+            let leadership = match pair.session {
+                Some(ref holder) if *holder == session => Some(Leadership::Leader { value: pair.value }),
+                Some(_) => Some(Leadership::Follower { leader: Some(pair.value) }),
+                None => None,
+            };
+            if let Some(leadership) = leadership {
+                let _ = changes.unbounded_send(Ok(leadership));
+                return Box::new(future::ok(Loop::Continue(Some(meta.last_index))));
+            }
+        }
+
+        let put = PutOptions { flags: Some(LOCK_FLAG_VALUE), acquire: Some(session), ..Default::default() };
+        let last_index = meta.last_index;
+        Box::new(client.kv().put_with(&key, value, &put).and_then(move |acquired| -> Box<dyn Future<Item = _, Error = _>> {
+            if acquired {
+                return Box::new(future::ok(Loop::Continue(Some(last_index))));
+            }
+            // refused while the previous leader's lock-delay is in effect
+            let _ = changes.unbounded_send(Ok(Leadership::Follower { leader: None }));
+            match Timeout::new(retry_delay, client.handle()) {
+                Ok(timeout) => Box::new(timeout.map(|()| Loop::Continue(None)).map_err(Error::from)),
+                Err(e) => Box::new(future::err(e.into())),
+            }
+        }))
+    }))
+}
+
+/// Stream of leadership changes returned by `LeaderElection::campaign`.
+///
+/// Only changes are yielded: a new role or a new leader value. Errors are
+/// retried and a lost session is replaced, the stream only fails with
+/// `Error::Conflict` when the key is used by something else than a lock.
+pub struct Election {
+    changes: mpsc::UnboundedReceiver<Result<Leadership, Error>>,
+    last: Option<Leadership>,
+    _stop: oneshot::Sender<()>,
+}
+
+impl Election {
+    /// Role reported last
+    pub fn current(&self) -> Option<&Leadership> {
+        self.last.as_ref()
+    }
+}
+
+impl Stream for Election {
+    type Item = Leadership;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Leadership>, Error> {
+        loop {
+            match self.changes.poll() {
+                Ok(Async::Ready(Some(Ok(leadership)))) => {
+                    if self.last.as_ref() != Some(&leadership) {
+                        self.last = Some(leadership.clone());
+                        return Ok(Async::Ready(Some(leadership)));
+                    }
+                }
+                Ok(Async::Ready(Some(Err(e)))) => return Err(e),
+                Ok(Async::Ready(None)) | Err(()) => return Ok(Async::Ready(None)),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::Stream;
+    use tokio_core::reactor::Core;
+
+    use mock::{self, KvEntry, Reply};
+    use {Client, Error, LockOptions, LOCK_FLAG_VALUE};
+    use super::{LeaderElection, Leadership};
+
+    #[test]
+    fn reports_leaders() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"s1"}"#),
+            Reply::new(404, "").header("X-Consul-Index", "9"),
+            Reply::new(200, "true"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("service/leader").flags(LOCK_FLAG_VALUE).value("a").session("s1")])).header("X-Consul-Index", "10"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("service/leader").flags(LOCK_FLAG_VALUE).value("b").session("other")])).header("X-Consul-Index", "12"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("service/leader").flags(LOCK_FLAG_VALUE).value("b").session("other")])).header("X-Consul-Index", "13"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("service/leader").flags(LOCK_FLAG_VALUE).value("c").session("other")])).header("X-Consul-Index", "14"),
+            Reply::new(200, "true"),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let mut options = LockOptions::new("service/leader");
+        options.value = b"a".to_vec();
+        let election = LeaderElection::new(&client, options).campaign();
+        let changes = core.run(election.take(3).collect()).unwrap();
+        assert_eq!(changes, vec![
+            Leadership::Leader { value: b"a".to_vec() },
+            Leadership::Follower { leader: Some(b"b".to_vec()) },
+            Leadership::Follower { leader: Some(b"c".to_vec()) },
+        ]);
+
+        let paths: Vec<_> = requests.iter().take(7).map(|req| req.path).collect();
+        assert_eq!(paths, vec![
+            "/v1/session/create",
+            "/v1/kv/service/leader?wait=15000ms",
+            "/v1/kv/service/leader?flags=3304740253564472344&acquire=s1",
+            "/v1/kv/service/leader?index=9&wait=15000ms",
+            "/v1/kv/service/leader?index=10&wait=15000ms",
+            "/v1/kv/service/leader?index=12&wait=15000ms",
+            "/v1/kv/service/leader?index=13&wait=15000ms",
+        ]);
+
+        // stepping down destroys the session
+        loop {
+            if mock::next_request(&mut core, &requests).path == "/v1/session/destroy/s1" {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_the_session_through_errors() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(500, "No cluster leader"),
+            Reply::new(200, r#"{"ID":"s1"}"#),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("service/leader").flags(LOCK_FLAG_VALUE).value("a").session("s1")])).header("X-Consul-Index", "10"),
+            Reply::new(500, "rpc error"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("service/leader").flags(LOCK_FLAG_VALUE).value("a").session("s1")])).header("X-Consul-Index", "11"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("service/leader").flags(LOCK_FLAG_VALUE).value("b").session("other")])).header("X-Consul-Index", "12"),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let mut options = LockOptions::new("service/leader");
+        options.value = b"a".to_vec();
+        options.retry_delay = Duration::from_millis(10);
+        options.monitor_retries = 1;
+        let election = LeaderElection::new(&client, options).campaign();
+        let changes = core.run(election.take(3).collect()).unwrap();
+        assert_eq!(changes, vec![
+            // the first term ended without a session
+            Leadership::Follower { leader: None },
+            Leadership::Leader { value: b"a".to_vec() },
+            Leadership::Follower { leader: Some(b"b".to_vec()) },
+        ]);
+
+        let paths: Vec<_> = requests.iter().take(6).map(|req| req.path).collect();
+        assert_eq!(paths, vec![
+            "/v1/session/create",
+            "/v1/session/create",
+            "/v1/kv/service/leader?wait=15000ms",
+            "/v1/kv/service/leader?index=10&wait=15000ms",
+            "/v1/kv/service/leader?index=10&wait=15000ms",
+            "/v1/kv/service/leader?index=11&wait=15000ms",
+        ]);
+    }
+
+    #[test]
+    fn steps_down_once_queries_fail() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"s1"}"#),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("service/leader").flags(LOCK_FLAG_VALUE).value("a").session("s1")]))
+                .header("X-Consul-Index", "10"),
+            Reply::new(500, "No cluster leader"),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let mut options = LockOptions::new("service/leader");
+        options.value = b"a".to_vec();
+        let election = LeaderElection::new(&client, options).campaign();
+        let changes = core.run(election.take(2).collect()).unwrap();
+        assert_eq!(changes, vec![
+            Leadership::Leader { value: b"a".to_vec() },
+            Leadership::Follower { leader: None },
+        ]);
+        let paths: Vec<_> = requests.iter().take(3).map(|req| req.path).collect();
+        assert_eq!(paths, vec![
+            "/v1/session/create",
+            "/v1/kv/service/leader?wait=15000ms",
+            "/v1/kv/service/leader?index=10&wait=15000ms",
+        ]);
+        // the session goes along with the leadership
+        assert_eq!(mock::next_request(&mut core, &requests).path, "/v1/session/destroy/s1");
+    }
+
+    #[test]
+    fn fails_on_a_key_not_used_as_a_lock() {
+        let (url, _requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"s1"}"#),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("service/leader").value("config")])).header("X-Consul-Index", "10"),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let election = LeaderElection::new(&client, LockOptions::new("service/leader")).campaign();
+        match core.run(election.collect()) {
+            Err(Error::Conflict(_)) => {}
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Event endpoint
+
+use std::collections::{HashSet, VecDeque};
+
+use futures::{Async, Future, Poll, Stream};
+use hyper::Method;
+use hyper::header::ContentType;
+
+use {append_query, escape, json_response, Client, Error, QueryMeta, QueryOptions, Watch, WriteOptions};
+
+/// UserEvent is an event fired through the gossip pool
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserEvent {
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(default, rename = "Payload", with = "::base64_value")]
+    pub payload: Vec<u8>,
+    #[serde(default, rename = "NodeFilter")]
+    pub node_filter: String,
+    #[serde(default, rename = "ServiceFilter")]
+    pub service_filter: String,
+    #[serde(default, rename = "TagFilter")]
+    pub tag_filter: String,
+    #[serde(default, rename = "Version")]
+    pub version: u32,
+    #[serde(default, rename = "LTime")]
+    pub ltime: u64,
+}
+
+/// Restricts which agents act on a fired event, the values are regular
+/// expressions
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub node: Option<String>,
+    pub service: Option<String>,
+    /// Only taken into account along with `service`
+    pub tag: Option<String>,
+}
+
+impl EventFilter {
+    fn params(&self) -> Vec<String> {
+        let mut params = Vec::new();
+        if let Some(ref node) = self.node {
+            params.push(format!("node={}", escape::query(node)));
+        }
+        if let Some(ref service) = self.service {
+            params.push(format!("service={}", escape::query(service)));
+        }
+        if let Some(ref tag) = self.tag {
+            params.push(format!("tag={}", escape::query(tag)));
+        }
+        params
+    }
+}
+
+/// Event endpoint
+pub struct Event<'a> {
+    client: &'a Client,
+}
+
+impl<'a> Event<'a> {
+    pub(crate) fn new(client: &'a Client) -> Self {
+        Event { client }
+    }
+
+    /// Fires an event, resolves to the event as the agent recorded it
+    pub fn fire(&self, name: &str, payload: Vec<u8>, filter: &EventFilter) -> Box<dyn Future<Item = UserEvent, Error = Error>> {
+        self.fire_with(name, payload, filter, &WriteOptions::default())
+    }
+
+    /// Same as `fire`, with options such as a token of its own
+    pub fn fire_with(&self, name: &str, payload: Vec<u8>, filter: &EventFilter, options: &WriteOptions) -> Box<dyn Future<Item = UserEvent, Error = Error>> {
+        let mut uri: String = "/v1/event/fire/".into();
+        uri.push_str(&escape::segment(name));
+        append_query(&mut uri, filter.params());
+        Box::new(json_response(self.client.request(Method::Put, &uri, options.token.as_deref(), ContentType::octet_stream(), payload))
+        .and_then(|(event, _)| event.ok_or_else(|| Error::NotFound("event fire returned 404".into()))))
+    }
+
+    /// Lists the most recent events known to the agent, optionally only
+    /// the ones called `name`
+    pub fn list(&self, name: Option<&str>) -> Box<dyn Future<Item = Vec<UserEvent>, Error = Error>> {
+        Box::new(self.list_with(name, &QueryOptions::default()).map(|(events, _)| events))
+    }
+
+    /// Same as `list`, but allows blocking queries
+    pub fn list_with(&self, name: Option<&str>, options: &QueryOptions) -> Box<dyn Future<Item = (Vec<UserEvent>, QueryMeta), Error = Error>> {
+        let mut uri: String = "/v1/event/list".into();
+        if let Some(name) = name {
+            append_query(&mut uri, vec![format!("name={}", escape::query(name))]);
+        }
+        Box::new(self.client.get_json(&uri, options)
+        .map(|(events, meta)| (events.unwrap_or_default(), meta)))
+    }
+
+    /// Streams events as they arrive, each one once.
+    ///
+    /// The events still buffered by the agent come out first, use
+    /// `EventWatch::skip_existing` to only see the ones fired afterwards.
+    pub fn watch(&self, name: Option<&str>, options: &QueryOptions) -> EventWatch {
+        let client = self.client.clone();
+        let name = name.map(|name| name.to_string());
+        let lists = Watch::new(self.client.handle(), options.clone(), move |options| {
+            client.event().list_with(name.as_deref(), options)
+        });
+        EventWatch { lists, seen: None, skip_existing: false, pending: VecDeque::new() }
+    }
+}
+
+/// Stream of user events returned by `Event::watch`.
+///
+/// The agent answers with its whole buffer of recent events every time,
+/// events are told apart by their ID so that each is yielded once.
+pub struct EventWatch {
+    lists: Watch<Vec<UserEvent>>,
+    seen: Option<HashSet<String>>,
+    skip_existing: bool,
+    pending: VecDeque<UserEvent>,
+}
+
+impl EventWatch {
+    /// Leaves out the events fired before the first query
+    pub fn skip_existing(mut self) -> Self {
+        self.skip_existing = true;
+        self
+    }
+}
+
+impl Stream for EventWatch {
+    type Item = UserEvent;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<UserEvent>, Error> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Async::Ready(Some(event)));
+            }
+            let events = match self.lists.poll()? {
+                Async::Ready(Some(events)) => events,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            // only the IDs still in the buffer are kept
+            let ids = events.iter().map(|event| event.id.clone()).collect();
+            match self.seen.replace(ids) {
+                Some(seen) => self.pending.extend(events.into_iter().filter(|event| !seen.contains(&event.id))),
+                None if !self.skip_existing => self.pending.extend(events),
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Stream;
+    use tokio_core::reactor::Core;
+
+    use mock::{self, Reply};
+    use {Client, EventFilter};
+
+    fn event(id: &str) -> String {
+        format!(r#"{{"ID":"{}","Name":"flush","Payload":"Y2FjaGU=","NodeFilter":"","ServiceFilter":"web","TagFilter":"","Version":1,"LTime":3}}"#, id)
+    }
+
+    #[test]
+    fn fire_and_list() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, &event("e1")),
+            Reply::new(200, &format!("[{}]", event("e1"))),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let filter = EventFilter { service: Some("web".into()), tag: Some("v1.2".into()), ..Default::default() };
+        let fired = core.run(client.event().fire("flush", b"cache".to_vec(), &filter)).unwrap();
+        let req = requests.recv().unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("PUT", "/v1/event/fire/flush?service=web&tag=v1.2"));
+        assert_eq!(req.body, b"cache".to_vec());
+        assert_eq!(fired.id, "e1");
+
+        let events = core.run(client.event().list(Some("flush"))).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/event/list?name=flush");
+        assert_eq!(events[0].payload, b"cache".to_vec());
+        assert_eq!(events[0].service_filter, "web");
+    }
+
+    #[test]
+    fn watch_yields_each_event_once() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, &format!("[{}]", event("e1"))).header("X-Consul-Index", "100"),
+            Reply::new(200, &format!("[{},{}]", event("e1"), event("e2"))).header("X-Consul-Index", "200"),
+            Reply::new(200, &format!("[{},{},{}]", event("e1"), event("e2"), event("e3"))).header("X-Consul-Index", "300"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let events = core.run(client.event().watch(Some("flush"), &Default::default()).take(3).collect()).unwrap();
+        let ids: Vec<_> = events.iter().map(|event| event.id.as_str()).collect();
+        assert_eq!(ids, vec!["e1", "e2", "e3"]);
+
+        let paths: Vec<_> = requests.iter().take(3).map(|req| req.path).collect();
+        assert_eq!(paths, vec![
+            "/v1/event/list?name=flush",
+            "/v1/event/list?name=flush&index=100",
+            "/v1/event/list?name=flush&index=200",
+        ]);
+    }
+}
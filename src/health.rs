@@ -0,0 +1,175 @@
+//! Health endpoint
+
+use futures::Future;
+
+use {append_query, escape, Client, Error, HealthService, QueryMeta, QueryOptions, Watch};
+
+/// Status of a health check
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Passing,
+    Warning,
+    Critical,
+    Maintenance,
+}
+
+impl CheckStatus {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CheckStatus::Passing => "passing",
+            CheckStatus::Warning => "warning",
+            CheckStatus::Critical => "critical",
+            CheckStatus::Maintenance => "maintenance",
+        }
+    }
+}
+
+/// HealthCheck is the state of a single check
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HealthCheck {
+    #[serde(rename = "Node")]
+    pub node: String,
+    #[serde(rename = "CheckID")]
+    pub check_id: String,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Status")]
+    pub status: CheckStatus,
+    #[serde(default, rename = "Notes")]
+    pub notes: String,
+    #[serde(default, rename = "Output")]
+    pub output: String,
+    #[serde(default, rename = "ServiceID")]
+    pub service_id: String,
+    #[serde(default, rename = "ServiceName")]
+    pub service_name: String,
+    #[serde(default, rename = "ServiceTags")]
+    pub service_tags: Option<Vec<String>>,
+    #[serde(default, rename = "CreateIndex")]
+    pub create_index: u64,
+    #[serde(default, rename = "ModifyIndex")]
+    pub modify_index: u64,
+}
+
+/// Health endpoint
+pub struct Health<'a> {
+    client: &'a Client,
+}
+
+impl<'a> Health<'a> {
+    pub(crate) fn new(client: &'a Client) -> Self {
+        Health { client }
+    }
+
+    /// Lists the instances of a service along with their node and checks.
+    ///
+    /// With `passing_only` instances having any check not passing are left
+    /// out, which is what service discovery usually wants.
+    pub fn service(&self, name: &str, tag: Option<&str>, passing_only: bool) -> Box<dyn Future<Item = Vec<HealthService>, Error = Error>> {
+        Box::new(self.service_with(name, tag, passing_only, &QueryOptions::default()).map(|(services, _)| services))
+    }
+
+    /// Same as `service`, but allows blocking queries
+    pub fn service_with(&self, name: &str, tag: Option<&str>, passing_only: bool, options: &QueryOptions) -> Box<dyn Future<Item = (Vec<HealthService>, QueryMeta), Error = Error>> {
+        let mut uri: String = "/v1/health/service/".into();
+        uri.push_str(&escape::segment(name));
+        let mut params = Vec::new();
+        if let Some(tag) = tag {
+            params.push(format!("tag={}", escape::query(tag)));
+        }
+        if passing_only {
+            params.push("passing".to_string());
+        }
+        append_query(&mut uri, params);
+        Box::new(self.client.get_json(&uri, options)
+        .map(|(services, meta)| (services.unwrap_or_default(), meta)))
+    }
+
+    /// Lists the checks of a service
+    pub fn checks(&self, service: &str) -> Box<dyn Future<Item = Vec<HealthCheck>, Error = Error>> {
+        Box::new(self.checks_with(service, &QueryOptions::default()).map(|(checks, _)| checks))
+    }
+
+    /// Same as `checks`, but allows blocking queries
+    pub fn checks_with(&self, service: &str, options: &QueryOptions) -> Box<dyn Future<Item = (Vec<HealthCheck>, QueryMeta), Error = Error>> {
+        let mut uri: String = "/v1/health/checks/".into();
+        uri.push_str(&escape::segment(service));
+        Box::new(self.client.get_json(&uri, options)
+        .map(|(checks, meta)| (checks.unwrap_or_default(), meta)))
+    }
+
+    /// Lists the checks of a node
+    pub fn node(&self, node: &str) -> Box<dyn Future<Item = Vec<HealthCheck>, Error = Error>> {
+        Box::new(self.node_with(node, &QueryOptions::default()).map(|(checks, _)| checks))
+    }
+
+    /// Same as `node`, but allows blocking queries
+    pub fn node_with(&self, node: &str, options: &QueryOptions) -> Box<dyn Future<Item = (Vec<HealthCheck>, QueryMeta), Error = Error>> {
+        let mut uri: String = "/v1/health/node/".into();
+        uri.push_str(&escape::segment(node));
+        Box::new(self.client.get_json(&uri, options)
+        .map(|(checks, meta)| (checks.unwrap_or_default(), meta)))
+    }
+
+    /// Lists the checks in the given state, `None` lists all of them
+    pub fn state(&self, state: Option<CheckStatus>) -> Box<dyn Future<Item = Vec<HealthCheck>, Error = Error>> {
+        Box::new(self.state_with(state, &QueryOptions::default()).map(|(checks, _)| checks))
+    }
+
+    /// Same as `state`, but allows blocking queries
+    pub fn state_with(&self, state: Option<CheckStatus>, options: &QueryOptions) -> Box<dyn Future<Item = (Vec<HealthCheck>, QueryMeta), Error = Error>> {
+        let mut uri: String = "/v1/health/state/".into();
+        uri.push_str(state.map(|state| state.as_str()).unwrap_or("any"));
+        Box::new(self.client.get_json(&uri, options)
+        .map(|(checks, meta)| (checks.unwrap_or_default(), meta)))
+    }
+
+    /// Watches the instances of a service and their health
+    pub fn watch_service(&self, name: &str, tag: Option<&str>, passing_only: bool, options: &QueryOptions) -> Watch<Vec<HealthService>> {
+        let client = self.client.clone();
+        let name = name.to_string();
+        let tag = tag.map(|tag| tag.to_string());
+        Watch::new(self.client.handle(), options.clone(), move |options| {
+            client.health().service_with(&name, tag.as_deref(), passing_only, options)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::Core;
+
+    use mock::{self, Reply};
+    use {CheckStatus, Client};
+
+    #[test]
+    fn health() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"[{"Node":{"ID":"40e4a748","Node":"foobar","Address":"10.1.10.12","Datacenter":"dc1"},
+                                "Service":{"ID":"redis","Service":"redis","Tags":["primary"],"Address":"10.1.10.12","Port":8000},
+                                "Checks":[{"Node":"foobar","CheckID":"service:redis","Name":"Service 'redis' check","Status":"passing",
+                                           "Notes":"","Output":"","ServiceID":"redis","ServiceName":"redis","ServiceTags":["primary"]},
+                                          {"Node":"foobar","CheckID":"serfHealth","Name":"Serf Health Status","Status":"passing",
+                                           "Notes":"","Output":"","ServiceID":"","ServiceName":"","ServiceTags":[]}]}]"#),
+            Reply::new(200, r#"[{"Node":"foobar","CheckID":"service:redis","Name":"Service 'redis' check","Status":"critical",
+                                 "Notes":"","Output":"connection refused","ServiceID":"redis","ServiceName":"redis"}]"#),
+            Reply::new(200, "[]"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let services = core.run(client.health().service("redis", Some("primary"), true)).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/health/service/redis?tag=primary&passing");
+        assert_eq!(services[0].service.address, "10.1.10.12");
+        assert_eq!(services[0].checks.len(), 2);
+        assert_eq!(services[0].checks[0].status, CheckStatus::Passing);
+
+        let checks = core.run(client.health().state(Some(CheckStatus::Critical))).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/health/state/critical");
+        assert_eq!(checks[0].output, "connection refused");
+
+        core.run(client.health().state(None)).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/health/state/any");
+    }
+}
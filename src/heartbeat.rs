@@ -0,0 +1,140 @@
+//! Background updates of TTL checks
+
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use futures::sync::oneshot;
+use tokio_core::reactor::Interval;
+
+use {CheckStatus, Client, Error};
+
+/// Shortest time between two updates, so that a tiny TTL does not flood
+/// the agent
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Keeps a TTL check alive by reporting the result of `health` to the
+/// agent at a fraction of the TTL
+pub struct Heartbeat<F> {
+    client: Client,
+    check_id: String,
+    interval: Duration,
+    deregister: bool,
+    health: F,
+}
+
+impl<F> Heartbeat<F> where F: FnMut() -> (CheckStatus, String) + 'static {
+    /// Reports every third of `ttl`, so that a single lost update does not
+    /// turn the check critical. Updates are never sent more often than
+    /// every 100ms.
+    pub fn new(client: &Client, check_id: &str, ttl: Duration, health: F) -> Self {
+        Heartbeat {
+            client: client.clone(),
+            check_id: check_id.to_string(),
+            interval: ttl / 3,
+            deregister: false,
+            health,
+        }
+    }
+
+    /// Overrides the time between two updates, which is never shorter
+    /// than 100ms
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Deregisters the check once the heartbeat is stopped
+    pub fn deregister_on_drop(mut self, deregister: bool) -> Self {
+        self.deregister = deregister;
+        self
+    }
+
+    /// Starts reporting on the client's reactor, the first update is sent
+    /// right away.
+    ///
+    /// The updates go on until the returned guard is dropped. Failed
+    /// updates are not fatal, the next tick simply tries again.
+    pub fn spawn(self) -> Result<HeartbeatGuard, Error> {
+        let Heartbeat { client, check_id, interval, deregister, mut health } = self;
+        let handle = client.handle().clone();
+        let ticks = Interval::new_at(Instant::now(), interval.max(MIN_INTERVAL), &handle)?;
+        let (stop, stopped) = oneshot::channel::<()>();
+
+        let beats = {
+            let client = client.clone();
+            let check_id = check_id.clone();
+            ticks.for_each(move |_| {
+                let (status, output) = health();
+                client.agent().update_check(&check_id, status, &output).then(|_| Ok(()))
+            })
+        };
+        let task = beats.then(|_| Ok::<(), ()>(()))
+            .select(stopped.then(|_| Ok(())))
+            .then(move |_| -> Box<dyn Future<Item = (), Error = ()>> {
+                if deregister {
+                    return Box::new(client.agent().deregister_check(&check_id).then(|_| Ok(())));
+                }
+                Box::new(future::ok(()))
+            });
+        handle.spawn(task);
+        Ok(HeartbeatGuard { _stop: stop })
+    }
+}
+
+/// Stops the heartbeat it was returned from when dropped
+pub struct HeartbeatGuard {
+    _stop: oneshot::Sender<()>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tokio_core::reactor::Core;
+
+    use mock::{self, Reply};
+    use {CheckStatus, Client};
+    use super::Heartbeat;
+
+    #[test]
+    fn reports_and_deregisters() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, ""),
+            Reply::new(200, ""),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let guard = Heartbeat::new(&client, "service:worker", Duration::from_secs(3600), || {
+            (CheckStatus::Warning, "degraded".to_string())
+        }).deregister_on_drop(true).spawn().unwrap();
+
+        let req = mock::next_request(&mut core, &requests);
+        assert_eq!(req.path, "/v1/agent/check/update/service:worker");
+        assert_eq!(req.body, br#"{"Status":"warning","Output":"degraded"}"#.to_vec());
+
+        drop(guard);
+        let req = mock::next_request(&mut core, &requests);
+        assert_eq!(req.path, "/v1/agent/check/deregister/service:worker");
+    }
+
+    #[test]
+    fn zero_ttl_is_clamped() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, ""),
+            Reply::new(200, ""),
+            Reply::new(200, ""),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let _guard = Heartbeat::new(&client, "service:worker", Duration::from_secs(0), || {
+            (CheckStatus::Passing, String::new())
+        }).spawn().unwrap();
+
+        mock::next_request(&mut core, &requests);
+        let start = Instant::now();
+        mock::next_request(&mut core, &requests);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
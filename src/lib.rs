@@ -3,23 +3,151 @@ extern crate hyper;
 extern crate tokio_core;
 extern crate serde;
 extern crate serde_json;
+extern crate base64;
+extern crate tokio_io;
+#[cfg(feature = "tls")]
+extern crate native_tls;
+#[cfg(feature = "tls")]
+extern crate tokio_tls;
+#[cfg(unix)]
+extern crate tokio_uds;
+#[macro_use]
+extern crate percent_encoding;
 #[macro_use]
 extern crate serde_derive;
 
-use std::sync::Arc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
 
 use hyper::Client as HyperClient;
-use hyper::client::HttpConnector;
-use hyper::{Uri, Request, Method};
-use futures::{Future, Stream};
+use hyper::{Uri, Request, Response, Method, StatusCode};
+use hyper::header::{ContentLength, ContentType};
+use futures::{future, Future, Stream};
+use futures::future::Shared;
+use futures::sync::oneshot;
 use tokio_core::reactor::Handle;
 
 use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+#[cfg(test)]
+mod mock;
+mod acl;
+mod catalog;
+mod connector;
+mod election;
+mod event;
+mod health;
+mod heartbeat;
+mod lock;
+mod semaphore;
+mod session;
+mod txn;
+mod watch;
 
+use acl::Login;
+use connector::{Connector, TlsConfig};
+pub use acl::{Acl, AclAuthMethod, AclBindingRule, AclLink, AclNodeIdentity, AclPolicy, AclRole, AclServiceIdentity, AclToken, BindType};
+pub use catalog::{Catalog, CatalogNode, CatalogService};
+pub use election::{Election, LeaderElection, Leadership};
+pub use event::{Event, EventFilter, EventWatch, UserEvent};
+pub use health::{CheckStatus, Health, HealthCheck};
+pub use heartbeat::{Heartbeat, HeartbeatGuard};
+pub use lock::{HeldLock, Lock, LockOptions, LOCK_FLAG_VALUE};
+pub use semaphore::{HeldSemaphore, Semaphore, SemaphoreOptions, SEMAPHORE_FLAG_VALUE};
+pub use session::{CreateSession, Session, SessionBehavior, SessionEntry, SessionKeeper, ServiceCheck};
+pub use txn::{TxnError, TxnOp};
+pub use watch::Watch;
+
+/// Everything a call can fail with
 #[derive(Debug)]
 pub enum Error {
+    /// The connection to the agent failed
     Http(hyper::Error),
-    Consul(String)
+    /// Reading a token or certificate file, or a socket failed
+    Io(io::Error),
+    /// A body could not be encoded or decoded
+    Serialization(serde_json::Error),
+    /// The URI of the agent or of a request is not valid
+    InvalidUri(hyper::error::UriError),
+    /// 404, for calls where a missing resource is not an answer in itself
+    NotFound(String),
+    /// 403, the token is missing, unknown (`ACL not found`) or lacks
+    /// permissions
+    PermissionDenied(String),
+    /// 409, or a key that is already used in an incompatible way
+    Conflict(String),
+    /// 429, the agent's request rate limit was hit
+    RateLimited(String),
+    /// Any other unsuccessful status, along with the body the agent sent
+    ServerError { status: u16, body: String },
+    /// A transaction was rolled back, one entry per failed operation
+    TxnRollback(Vec<TxnError>),
+    /// Options that cannot work, e.g. a semaphore without any slot
+    InvalidOptions(String),
+    /// The TLS certificates or keys could not be loaded
+    #[cfg(feature = "tls")]
+    Tls(native_tls::Error),
+}
+
+impl Error {
+    /// Maps an unsuccessful response to the matching variant
+    fn from_status(status: StatusCode, body: &[u8]) -> Self {
+        let body = String::from_utf8_lossy(body).trim().to_string();
+        match status {
+            StatusCode::NotFound => Error::NotFound(body),
+            StatusCode::Forbidden => Error::PermissionDenied(body),
+            StatusCode::Conflict => Error::Conflict(body),
+            StatusCode::TooManyRequests => Error::RateLimited(body),
+            status => Error::ServerError { status: status.as_u16(), body },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::Http(ref e) => write!(f, "HTTP error: {}", e),
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Serialization(ref e) => write!(f, "serialization error: {}", e),
+            Error::InvalidUri(ref e) => write!(f, "invalid URI: {}", e),
+            Error::NotFound(ref body) => write!(f, "not found: {}", body),
+            Error::PermissionDenied(ref body) => write!(f, "permission denied: {}", body),
+            Error::Conflict(ref body) => write!(f, "conflict: {}", body),
+            Error::RateLimited(ref body) => write!(f, "rate limited: {}", body),
+            Error::ServerError { status, ref body } => write!(f, "unexpected status {}: {}", status, body),
+            Error::InvalidOptions(ref reason) => write!(f, "invalid options: {}", reason),
+            Error::TxnRollback(ref errors) => {
+                write!(f, "transaction rolled back")?;
+                for error in errors {
+                    write!(f, ", op {}: {}", error.op_index, error.what)?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "tls")]
+            Error::Tls(ref e) => write!(f, "TLS error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::Http(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            Error::Serialization(ref e) => Some(e),
+            Error::InvalidUri(ref e) => Some(e),
+            #[cfg(feature = "tls")]
+            Error::Tls(ref e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<hyper::Error> for Error {
@@ -28,13 +156,50 @@ impl From<hyper::Error> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Serialization(e)
+    }
+}
+
+impl From<hyper::error::UriError> for Error {
+    fn from(e: hyper::error::UriError) -> Self {
+        Error::InvalidUri(e)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<native_tls::Error> for Error {
+    fn from(e: native_tls::Error) -> Self {
+        Error::Tls(e)
+    }
+}
+
 /// Node represents a node
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Node {
+    #[serde(default, rename = "ID")]
+    pub id: String,
     #[serde(rename = "Node")]
     pub node: String,
     #[serde(rename = "Address")]
     pub address: String,
+    #[serde(default, rename = "Datacenter")]
+    pub datacenter: String,
+    #[serde(default, rename = "TaggedAddresses")]
+    pub tagged_addresses: Option<HashMap<String, String>>,
+    #[serde(default, rename = "Meta")]
+    pub meta: Option<HashMap<String, String>>,
+    #[serde(default, rename = "CreateIndex")]
+    pub create_index: u64,
+    #[serde(default, rename = "ModifyIndex")]
+    pub modify_index: u64,
 }
 
 /// Service represents a service
@@ -48,15 +213,25 @@ pub struct Service {
     pub tags: Option<Vec<String>>,
     #[serde(rename = "Port")]
     pub port: u32,
+    #[serde(default, rename = "Address")]
+    pub address: String,
+    #[serde(default, rename = "Meta")]
+    pub meta: Option<HashMap<String, String>>,
+    #[serde(default, rename = "CreateIndex")]
+    pub create_index: u64,
+    #[serde(default, rename = "ModifyIndex")]
+    pub modify_index: u64,
 }
 
 /// HealthService is used for the health service
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct HealthService{
     #[serde(rename = "Node")]
     pub node: Node,
     #[serde(rename = "Service")]
     pub service: Service,
+    #[serde(default, rename = "Checks")]
+    pub checks: Vec<HealthCheck>,
 }
 
 /// Service represents a service
@@ -80,19 +255,337 @@ pub struct RegisterService {
 pub struct Check {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ttl: Option<String>,
+    /// Left out when empty, consul refuses an interval along with a TTL
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub interval: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub http: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub script: Option<String>,
-    #[serde(rename = "DeregisterCriticalServiceAfter")]
+    /// Left out when empty
+    #[serde(default, skip_serializing_if = "String::is_empty", rename = "DeregisterCriticalServiceAfter")]
     pub deregister_critical_service_after: String,
 }
 
+/// RegisterCheck is a check registered on its own rather than along with
+/// a service
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegisterCheck {
+    #[serde(skip_serializing_if = "Option::is_none", rename = "ID")]
+    pub id: Option<String>,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "ServiceID")]
+    pub service_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "Notes")]
+    pub notes: Option<String>,
+    /// Initial status, consul starts checks as critical by default
+    #[serde(skip_serializing_if = "Option::is_none", rename = "Status")]
+    pub status: Option<CheckStatus>,
+    #[serde(flatten)]
+    pub check: Check,
+}
+
+/// KVPair is a single entry of the key-value store
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KVPair {
+    #[serde(rename = "Key")]
+    pub key: String,
+    #[serde(rename = "CreateIndex")]
+    pub create_index: u64,
+    #[serde(rename = "ModifyIndex")]
+    pub modify_index: u64,
+    #[serde(rename = "LockIndex")]
+    pub lock_index: u64,
+    #[serde(rename = "Flags")]
+    pub flags: u64,
+    #[serde(rename = "Value", with = "base64_value")]
+    pub value: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "Session")]
+    pub session: Option<String>,
+}
+
+/// Optional parameters of a KV write
+#[derive(Debug, Default, Clone)]
+pub struct PutOptions {
+    /// Only write if the key's `ModifyIndex` still matches, `0` writes only
+    /// if the key does not exist yet
+    pub cas: Option<u64>,
+    /// Opaque value stored along with the key
+    pub flags: Option<u64>,
+    /// Acquire the lock on the key for the given session
+    pub acquire: Option<String>,
+    /// Release the lock on the key held by the given session
+    pub release: Option<String>,
+    /// ACL token used instead of the client's one
+    pub token: Option<String>,
+}
+
+impl PutOptions {
+    fn params(&self) -> Vec<String> {
+        let mut params = Vec::new();
+        if let Some(cas) = self.cas {
+            params.push(format!("cas={}", cas));
+        }
+        if let Some(flags) = self.flags {
+            params.push(format!("flags={}", flags));
+        }
+        if let Some(ref session) = self.acquire {
+            params.push(format!("acquire={}", escape::query(session)));
+        }
+        if let Some(ref session) = self.release {
+            params.push(format!("release={}", escape::query(session)));
+        }
+        params
+    }
+}
+
+/// Optional parameters of the writes other than KV puts
+#[derive(Debug, Default, Clone)]
+pub struct WriteOptions {
+    /// ACL token used instead of the client's one
+    pub token: Option<String>,
+}
+
+/// Parameters shared by all read requests
+#[derive(Debug, Default, Clone)]
+pub struct QueryOptions {
+    /// Query this datacenter instead of the agent's one
+    pub datacenter: Option<String>,
+    /// Let any server answer, not only the leader
+    pub allow_stale: bool,
+    /// Have the leader verify it is still the leader before answering
+    pub require_consistent: bool,
+    /// Turns the request into a blocking query which returns once the
+    /// result's index exceeds this value
+    pub index: Option<u64>,
+    /// Maximum time a blocking query may wait, consul defaults to 5 minutes
+    pub wait: Option<Duration>,
+    /// ACL token used instead of the client's one
+    pub token: Option<String>,
+}
+
+impl QueryOptions {
+    fn params(&self) -> Vec<String> {
+        let mut params = Vec::new();
+        if let Some(ref dc) = self.datacenter {
+            params.push(format!("dc={}", escape::query(dc)));
+        }
+        if self.allow_stale {
+            params.push("stale".into());
+        }
+        if self.require_consistent {
+            params.push("consistent".into());
+        }
+        if let Some(index) = self.index {
+            params.push(format!("index={}", index));
+        }
+        if let Some(wait) = self.wait {
+            params.push(format!("wait={}ms", wait.as_millis()));
+        }
+        params
+    }
+}
+
+/// Metadata returned along with the result of a read request
+#[derive(Debug, Default, Clone)]
+pub struct QueryMeta {
+    /// `X-Consul-Index`, pass it as `QueryOptions::index` to wait for changes
+    pub last_index: u64,
+    /// `X-Consul-LastContact`, time since the server last heard from the leader
+    pub last_contact: Duration,
+    /// `X-Consul-KnownLeader`, whether the cluster currently has a leader
+    pub known_leader: bool,
+}
+
+impl QueryMeta {
+    fn from_headers(headers: &hyper::Headers) -> Self {
+        fn header<'a>(headers: &'a hyper::Headers, name: &str) -> Option<&'a str> {
+            headers.get_raw(name)
+                .and_then(|raw| raw.one())
+                .and_then(|value| std::str::from_utf8(value).ok())
+        }
+        QueryMeta {
+            last_index: header(headers, "X-Consul-Index")
+                .and_then(|index| index.parse().ok())
+                .unwrap_or(0),
+            last_contact: header(headers, "X-Consul-LastContact")
+                .and_then(|millis| millis.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or_default(),
+            known_leader: header(headers, "X-Consul-KnownLeader") == Some("true"),
+        }
+    }
+}
+
+/// Response of a request, failing to build the request fails it as well
+type ResponseFuture = Box<dyn Future<Item = Response, Error = Error>>;
+
+/// Appends query parameters to `uri`, which may already carry some
+fn append_query(uri: &mut String, params: Vec<String>) {
+    for param in params {
+        uri.push(if uri.contains('?') { '&' } else { '?' });
+        uri.push_str(&param);
+    }
+}
+
+/// Percent-encoding of user supplied parts of the URI
+mod escape {
+    use percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET, PATH_SEGMENT_ENCODE_SET};
+
+    define_encode_set! {
+        /// `&`, `=` and `+` would change the meaning of a query
+        pub QUERY_VALUE_ENCODE_SET = [DEFAULT_ENCODE_SET] | {'%', '&', '=', '+'}
+    }
+
+    pub fn query(value: &str) -> String {
+        utf8_percent_encode(value, QUERY_VALUE_ENCODE_SET).to_string()
+    }
+
+    /// A single segment of the path such as a service ID, `/` included
+    pub fn segment(value: &str) -> String {
+        utf8_percent_encode(value, PATH_SEGMENT_ENCODE_SET).to_string()
+    }
+
+    /// A KV key or prefix, `/` is kept as the separator of the hierarchy
+    pub fn key(key: &str) -> String {
+        key.split('/').map(segment).collect::<Vec<_>>().join("/")
+    }
+}
+
+/// Consul transfers values base64-encoded, `null` stands for an empty value
+mod base64_value {
+    use serde::{Serializer, Deserializer, Deserialize};
+    use serde::de::Error;
+    use base64;
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(encoded) => base64::decode(&encoded).map_err(D::Error::custom),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
 /// Client for the consul API
+#[derive(Clone)]
 pub struct Client {
-    client: Arc<HyperClient<HttpConnector>>,
+    client: Rc<HyperClient<Connector>>,
     base_uri: Uri,
+    handle: Handle,
+    auth: Rc<RefCell<Auth>>,
+}
+
+/// Token sent by default, shared by the clones of a client so that a login
+/// applies to all of them
+#[derive(Default)]
+struct Auth {
+    token: Option<String>,
+    /// Credentials to log in again with once the token is gone
+    login: Option<Login>,
+    /// Check of the token and login in progress, see `Client::renew_login`
+    renewal: Option<Shared<oneshot::Receiver<Option<String>>>>,
+}
+
+/// How `Client::renew_login` goes on with a request refused with `ACL not
+/// found`
+enum Renewal {
+    /// The token is valid, the refusal is about something else
+    Valid,
+    /// The token was replaced, the request is to be repeated with this one
+    Repeat(String),
+    /// The refusal stands, e.g. logging in again failed
+    Refused,
+}
+
+/// Configuration of a `Client`, returned by `Client::builder`
+#[derive(Clone)]
+pub struct ClientBuilder {
+    url: String,
+    token: Option<String>,
+    tls: TlsConfig,
+}
+
+/// Leaves the token out, builders end up in logs
+impl fmt::Debug for ClientBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientBuilder")
+            .field("url", &self.url)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
+
+impl ClientBuilder {
+    /// Same as `Client::with_token`
+    pub fn token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Verifies the agent with the PEM encoded CA certificates in `path`
+    /// instead of the system roots
+    #[cfg(feature = "tls")]
+    pub fn ca_cert(mut self, path: &str) -> Self {
+        self.tls.ca_cert = Some(path.to_string());
+        self
+    }
+
+    /// Presents a client certificate to agents that verify incoming
+    /// connections, both files are PEM encoded and the key must be PKCS#8
+    #[cfg(feature = "tls")]
+    pub fn client_cert(mut self, cert_path: &str, key_path: &str) -> Self {
+        self.tls.client_cert = Some((cert_path.to_string(), key_path.to_string()));
+        self
+    }
+
+    /// Sends `name` as SNI and checks the agent's certificate against it
+    /// rather than the host of the URL, for agents reached by IP address
+    #[cfg(feature = "tls")]
+    pub fn tls_server_name(mut self, name: &str) -> Self {
+        self.tls.server_name = Some(name.to_string());
+        self
+    }
+
+    /// Accepts any certificate from the agent, which leaves the connection
+    /// open to interception
+    #[cfg(feature = "tls")]
+    pub fn insecure_skip_verify(mut self, skip: bool) -> Self {
+        self.tls.insecure_skip_verify = skip;
+        self
+    }
+
+    /// Loads the certificates and creates the client, `https` URLs are
+    /// only supported with the `tls` feature.
+    ///
+    /// A `unix:///path/to/consul_http.sock` URL sends every request over
+    /// that socket.
+    pub fn build(self, handle: &Handle) -> Result<Client, Error> {
+        let (uri, socket) = if self.url.starts_with("unix://") {
+            ("http://localhost".parse()?, Some(self.url["unix://".len()..].to_string()))
+        } else {
+            (self.url.parse()?, None)
+        };
+        let connector = Connector::new(handle, socket, &self.tls)?;
+        let client = HyperClient::configure().connector(connector).build(handle);
+        let auth = Auth { token: self.token, ..Default::default() };
+        Ok(Client { client: Rc::new(client), base_uri: uri, handle: handle.clone(), auth: Rc::new(RefCell::new(auth)) })
+    }
+}
+
+/// Reads a boolean the way the consul CLI does, `None` when unset or
+/// unrecognized
+fn env_flag(name: &str) -> Option<bool> {
+    match env::var(name).ok()?.to_lowercase().as_str() {
+        "1" | "t" | "true" => Some(true),
+        "0" | "f" | "false" => Some(false),
+        _ => None,
+    }
 }
 
 /// Agent endpoint
@@ -106,124 +599,1018 @@ pub struct KV<'a> {
 }
 
 impl Client {
-    pub fn new(handle: &Handle, url: &str) -> Result<Self, hyper::error::UriError> {
-        let uri = url.parse()?;
-        Ok(Client { client: Arc::new(HyperClient::new(handle)), base_uri: uri })
+    /// Creates a client for the agent at `url`, either `http://host:port`,
+    /// `https://host:port` or `unix:///path/to/consul_http.sock`
+    pub fn new(handle: &Handle, url: &str) -> Result<Self, Error> {
+        Client::builder(url).build(handle)
+    }
+
+    /// Starts configuring a client for the agent at `url`, needed for
+    /// anything beyond a plain `http` agent without a token
+    pub fn builder(url: &str) -> ClientBuilder {
+        ClientBuilder { url: url.to_string(), token: None, tls: TlsConfig::default() }
+    }
+
+    /// Configures the client the way the consul CLI does.
+    ///
+    /// The agent is taken from `CONSUL_HTTP_ADDR`, `127.0.0.1:8500` by
+    /// default, over `https` when `CONSUL_HTTP_SSL` is `true`. The ACL
+    /// token is read from the file named by `CONSUL_HTTP_TOKEN_FILE`, or
+    /// else taken from `CONSUL_HTTP_TOKEN`.
+    ///
+    /// TLS is set up from `CONSUL_CACERT`, `CONSUL_CLIENT_CERT`,
+    /// `CONSUL_CLIENT_KEY`, `CONSUL_TLS_SERVER_NAME` and
+    /// `CONSUL_HTTP_SSL_VERIFY`.
+    pub fn from_env(handle: &Handle) -> Result<Self, Error> {
+        let addr = env::var("CONSUL_HTTP_ADDR").unwrap_or_else(|_| "127.0.0.1:8500".into());
+        let scheme = if env_flag("CONSUL_HTTP_SSL") == Some(true) { "https" } else { "http" };
+        let url = if addr.contains("://") { addr } else { format!("{}://{}", scheme, addr) };
+        let mut builder = Client::builder(&url);
+
+        if let Ok(path) = env::var("CONSUL_HTTP_TOKEN_FILE") {
+            let token = fs::read_to_string(path)?;
+            if !token.trim().is_empty() {
+                builder = builder.token(token.trim());
+            }
+        }
+        match env::var("CONSUL_HTTP_TOKEN") {
+            Ok(ref token) if !token.is_empty() && builder.token.is_none() => builder = builder.token(token),
+            _ => {}
+        }
+        #[cfg(feature = "tls")]
+        {
+            let var = |name| env::var(name).ok().filter(|value: &String| !value.is_empty());
+            if let Some(path) = var("CONSUL_CACERT") {
+                builder = builder.ca_cert(&path);
+            }
+            if let (Some(cert), Some(key)) = (var("CONSUL_CLIENT_CERT"), var("CONSUL_CLIENT_KEY")) {
+                builder = builder.client_cert(&cert, &key);
+            }
+            if let Some(name) = var("CONSUL_TLS_SERVER_NAME") {
+                builder = builder.tls_server_name(&name);
+            }
+            if env_flag("CONSUL_HTTP_SSL_VERIFY") == Some(false) {
+                builder = builder.insecure_skip_verify(true);
+            }
+        }
+        builder.build(handle)
+    }
+
+    /// Sends `token` as `X-Consul-Token` with every request, unless the
+    /// options of a request carry their own
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.auth = Rc::new(RefCell::new(Auth { token: Some(token.to_string()), ..Default::default() }));
+        self
     }
 
-    pub fn agent(&self) -> Agent {
+    /// ACL token sent by default
+    pub fn token(&self) -> Option<String> {
+        self.auth.borrow().token.clone()
+    }
+
+    /// Replaces the token of this client and its clones
+    fn set_token(&self, token: Option<String>, login: Option<Login>) {
+        *self.auth.borrow_mut() = Auth { token, login, renewal: None };
+    }
+
+    pub fn agent(&self) -> Agent<'_> {
         Agent { client: self }
     }
 
-    pub fn kv(&self) -> KV {
+    pub fn kv(&self) -> KV<'_> {
         KV { client: self }
     }
 
-    fn request(&self, method: Method, path: &str, type_: hyper::header::ContentType, body: Vec<u8>) -> hyper::client::FutureResponse {
-        use hyper::header::ContentLength;
+    pub fn catalog(&self) -> Catalog<'_> {
+        Catalog::new(self)
+    }
 
-        let uri_str = format!("{}{}", self.base_uri, path);
-        let uri = uri_str.parse().unwrap();
+    pub fn health(&self) -> Health<'_> {
+        Health::new(self)
+    }
 
-        let mut req = Request::new(method, uri);
-        req.headers_mut().set(type_);
-        req.headers_mut().set(ContentLength(body.len() as u64));
-        req.set_body(body);
+    pub fn session(&self) -> Session<'_> {
+        Session::new(self)
+    }
 
-        let client = self.client.clone();
-        client.request(req)
+    pub fn event(&self) -> Event<'_> {
+        Event::new(self)
     }
 
-    fn request_json<T: Serialize>(&self, method: Method, path: &str, body: T) -> hyper::client::FutureResponse {
-        use hyper::header::{ContentLength,ContentType};
+    pub fn acl(&self) -> Acl<'_> {
+        Acl::new(self)
+    }
 
-        let json = serde_json::to_string(&body).unwrap();
-        let uri_str = format!("{}{}", self.base_uri, path);
-        let uri = uri_str.parse().unwrap();
-        let mut req = Request::new(method, uri);
-        req.headers_mut().set(ContentType::json());
-        req.headers_mut().set(ContentLength(json.as_bytes().len() as u64));
-        //println!("SENDING {}", json);
-        req.set_body(json);
+    fn handle(&self) -> &Handle {
+        &self.handle
+    }
 
-        let client = self.client.clone();
-        client.request(req)
+    fn uri(&self, path: &str) -> Result<Uri, Error> {
+        let base = self.base_uri.to_string();
+        Ok(format!("{}{}", base.trim_end_matches('/'), path).parse()?)
     }
-}
 
-impl<'a> Agent<'a> {
-    pub fn register(&self, service: RegisterService) -> Box<Future<Item = (), Error = Error>> {
-        Box::new(self.client.request_json(Method::Put, "/v1/agent/service/register", service)
-        .and_then(|resp| {
+    fn request_empty(&self, method: Method, path: &str, token: Option<&str>) -> ResponseFuture {
+        self.send(method, path, token, None)
+    }
+
+    /// GETs `path` and decodes the JSON response, a 404 yields `None`
+    fn get_json<T: DeserializeOwned + 'static>(&self, path: &str, options: &QueryOptions) -> Box<dyn Future<Item = (Option<T>, QueryMeta), Error = Error>> {
+        let mut uri = path.to_string();
+        append_query(&mut uri, options.params());
+        json_response(self.send(Method::Get, &uri, options.token.as_deref(), None))
+    }
+
+    fn request(&self, method: Method, path: &str, token: Option<&str>, type_: ContentType, body: Vec<u8>) -> ResponseFuture {
+        self.send(method, path, token, Some((type_, body)))
+    }
+
+    fn request_json<T: Serialize>(&self, method: Method, path: &str, token: Option<&str>, body: T) -> ResponseFuture {
+        match serde_json::to_vec(&body) {
+            Ok(json) => self.request(method, path, token, ContentType::json(), json),
+            Err(e) => Box::new(future::err(e.into())),
+        }
+    }
+
+    /// Sends a request with `token`, or else the client's token. A path
+    /// that does not make a valid URI fails the returned future.
+    ///
+    /// After a login, a request refused because the client's token is gone
+    /// logs in again and is repeated with the new token.
+    fn send(&self, method: Method, path: &str, token: Option<&str>, body: Option<(ContentType, Vec<u8>)>) -> ResponseFuture {
+        Box::new(self.send_checked(method, path, token, body).map(|(resp, _)| resp))
+    }
+
+    /// Same as `send`, also telling whether the token was found valid after
+    /// an `ACL not found` refusal, which then is about something else, e.g.
+    /// a missing ACL object
+    fn send_checked(&self, method: Method, path: &str, token: Option<&str>, body: Option<(ContentType, Vec<u8>)>) -> Box<dyn Future<Item = (Response, bool), Error = Error>> {
+        let relogin = token.is_none() && self.auth.borrow().login.is_some();
+        let token = token.map(|token| token.to_string()).or_else(|| self.token());
+        if !relogin {
+            return Box::new(self.send_as(method, path, token, body).map(|resp| (resp, false)));
+        }
+
+        let client = self.clone();
+        let path = path.to_string();
+        Box::new(self.send_as(method.clone(), &path, token.clone(), body.clone()).and_then(move |resp| -> Box<dyn Future<Item = _, Error = _>> {
+            if resp.status() != StatusCode::Forbidden {
+                return Box::new(future::ok((resp, false)));
+            }
             let status = resp.status();
-            resp.body().concat2().map(move |body| (status, body))
+            let headers = resp.headers().clone();
+            Box::new(resp.body().concat2().map_err(Error::from).and_then(move |refusal| -> Box<dyn Future<Item = _, Error = _>> {
+                let gone = String::from_utf8_lossy(&refusal).contains("ACL not found");
+                let refused = Response::new().with_status(status).with_headers(headers).with_body(refusal.to_vec());
+                if !gone {
+                    return Box::new(future::ok((refused, false)));
+                }
+                Box::new(client.renew_login(token).and_then(move |renewal| -> Box<dyn Future<Item = _, Error = _>> {
+                    match renewal {
+                        Renewal::Valid => Box::new(future::ok((refused, true))),
+                        Renewal::Repeat(token) => Box::new(client.send_as(method, &path, Some(token), body).map(|resp| (resp, true))),
+                        Renewal::Refused => Box::new(future::ok((refused, false))),
+                    }
+                }))
+            }))
+        }))
+    }
+
+    /// Finds out how to go on with a request refused with `ACL not found`
+    /// while sending `refused`: checks whether the token is gone and if so
+    /// logs in again.
+    ///
+    /// Requests refused at the same time share one check and one login.
+    fn renew_login(&self, refused: Option<String>) -> Box<dyn Future<Item = Renewal, Error = Error>> {
+        let current = self.token();
+        if current != refused {
+            // another request logged in again already, or the client logged out
+            return Box::new(future::ok(current.map_or(Renewal::Refused, Renewal::Repeat)));
+        }
+        let (login, renewal) = {
+            let auth = self.auth.borrow();
+            (auth.login.clone(), auth.renewal.clone())
+        };
+        if let Some(renewal) = renewal {
+            return Box::new(renewal.then(|token| Ok(match token {
+                Ok(token) => (*token).clone().map_or(Renewal::Valid, Renewal::Repeat),
+                Err(_) => Renewal::Refused,
+            })));
+        }
+        let login = match login {
+            Some(login) => login,
+            None => return Box::new(future::ok(Renewal::Refused)),
+        };
+
+        let (done, renewal) = oneshot::channel();
+        self.auth.borrow_mut().renewal = Some(renewal.shared());
+        let client = self.clone();
+        let check = self.send_as(Method::Get, "/v1/acl/token/self", refused, None);
+        Box::new(check.and_then({
+            let client = client.clone();
+            move |resp| -> Box<dyn Future<Item = _, Error = _>> {
+                if resp.status() != StatusCode::Forbidden {
+                    return Box::new(future::ok(None));
+                }
+                Box::new(login.perform(&client).map(|token| Some(token.secret_id)))
+            }
         })
-        .map_err(|e| e.into())
-        .and_then(|(status, body)| {
-            if status.is_success() {
-                return Ok(());
+        .then(move |token| {
+            // on failure `done` is dropped and the waiting requests keep their refusal
+            client.auth.borrow_mut().renewal = None;
+            if let Ok(ref token) = token {
+                let _ = done.send(token.clone());
             }
-            Err(Error::Consul(String::from_utf8_lossy(&body).to_string()))
+            token.map(|token| token.map_or(Renewal::Valid, Renewal::Repeat))
         }))
     }
+
+    /// Sends a request with exactly `token`
+    fn send_as(&self, method: Method, path: &str, token: Option<String>, body: Option<(ContentType, Vec<u8>)>) -> ResponseFuture {
+        let uri = match self.uri(path) {
+            Ok(uri) => uri,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let mut req = Request::new(method, uri);
+        if let Some(token) = token {
+            req.headers_mut().set_raw("X-Consul-Token", token);
+        }
+        if let Some((type_, body)) = body {
+            req.headers_mut().set(type_);
+            req.headers_mut().set(ContentLength(body.len() as u64));
+            req.set_body(body);
+        }
+        Box::new(self.client.request(req).map_err(Error::from))
+    }
+}
+
+impl<'a> Agent<'a> {
+    pub fn register(&self, service: RegisterService) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.register_with(service, &WriteOptions::default())
+    }
+
+    /// Same as `register`, with options such as a token of its own
+    pub fn register_with(&self, service: RegisterService, options: &WriteOptions) -> Box<dyn Future<Item = (), Error = Error>> {
+        unit_response(self.client.request_json(Method::Put, "/v1/agent/service/register", options.token.as_deref(), service))
+    }
+
+    /// Removes a service registered with this agent
+    pub fn deregister(&self, service_id: &str) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.deregister_with(service_id, &WriteOptions::default())
+    }
+
+    /// Same as `deregister`, with options such as a token of its own
+    pub fn deregister_with(&self, service_id: &str, options: &WriteOptions) -> Box<dyn Future<Item = (), Error = Error>> {
+        let mut uri: String = "/v1/agent/service/deregister/".into();
+        uri.push_str(&escape::segment(service_id));
+        unit_response(self.client.request_empty(Method::Put, &uri, options.token.as_deref()))
+    }
+
+    /// Lists the services registered with this agent by their ID
+    pub fn services(&self) -> Box<dyn Future<Item = HashMap<String, Service>, Error = Error>> {
+        self.services_with(&QueryOptions::default())
+    }
+
+    /// Same as `services`, with options such as a token of its own
+    pub fn services_with(&self, options: &QueryOptions) -> Box<dyn Future<Item = HashMap<String, Service>, Error = Error>> {
+        Box::new(self.client.get_json("/v1/agent/services", options)
+        .map(|(services, _)| services.unwrap_or_default()))
+    }
+
+    /// Fetches a service registered with this agent, returns `None` if
+    /// there is no such service
+    pub fn service(&self, service_id: &str) -> Box<dyn Future<Item = Option<Service>, Error = Error>> {
+        self.service_with(service_id, &QueryOptions::default())
+    }
+
+    /// Same as `service`, with options such as a token of its own
+    pub fn service_with(&self, service_id: &str, options: &QueryOptions) -> Box<dyn Future<Item = Option<Service>, Error = Error>> {
+        let mut uri: String = "/v1/agent/service/".into();
+        uri.push_str(&escape::segment(service_id));
+        Box::new(self.client.get_json(&uri, options)
+        .map(|(service, _)| service))
+    }
+
+    /// Registers a check with this agent
+    pub fn register_check(&self, check: RegisterCheck) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.register_check_with(check, &WriteOptions::default())
+    }
+
+    /// Same as `register_check`, with options such as a token of its own
+    pub fn register_check_with(&self, check: RegisterCheck, options: &WriteOptions) -> Box<dyn Future<Item = (), Error = Error>> {
+        unit_response(self.client.request_json(Method::Put, "/v1/agent/check/register", options.token.as_deref(), check))
+    }
+
+    /// Removes a check registered with this agent
+    pub fn deregister_check(&self, check_id: &str) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.deregister_check_with(check_id, &WriteOptions::default())
+    }
+
+    /// Same as `deregister_check`, with options such as a token of its own
+    pub fn deregister_check_with(&self, check_id: &str, options: &WriteOptions) -> Box<dyn Future<Item = (), Error = Error>> {
+        let mut uri: String = "/v1/agent/check/deregister/".into();
+        uri.push_str(&escape::segment(check_id));
+        unit_response(self.client.request_empty(Method::Put, &uri, options.token.as_deref()))
+    }
+
+    /// Lists the checks registered with this agent by their ID
+    pub fn checks(&self) -> Box<dyn Future<Item = HashMap<String, HealthCheck>, Error = Error>> {
+        self.checks_with(&QueryOptions::default())
+    }
+
+    /// Same as `checks`, with options such as a token of its own
+    pub fn checks_with(&self, options: &QueryOptions) -> Box<dyn Future<Item = HashMap<String, HealthCheck>, Error = Error>> {
+        Box::new(self.client.get_json("/v1/agent/checks", options)
+        .map(|(checks, _)| checks.unwrap_or_default()))
+    }
+
+    /// Marks a TTL check as passing and resets its timer
+    pub fn pass(&self, check_id: &str, note: Option<&str>) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.pass_with(check_id, note, &WriteOptions::default())
+    }
+
+    /// Same as `pass`, with options such as a token of its own
+    pub fn pass_with(&self, check_id: &str, note: Option<&str>, options: &WriteOptions) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.ttl_update("pass", check_id, note, options)
+    }
+
+    /// Marks a TTL check as warning and resets its timer
+    pub fn warn(&self, check_id: &str, note: Option<&str>) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.warn_with(check_id, note, &WriteOptions::default())
+    }
+
+    /// Same as `warn`, with options such as a token of its own
+    pub fn warn_with(&self, check_id: &str, note: Option<&str>, options: &WriteOptions) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.ttl_update("warn", check_id, note, options)
+    }
+
+    /// Marks a TTL check as critical and resets its timer
+    pub fn fail(&self, check_id: &str, note: Option<&str>) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.fail_with(check_id, note, &WriteOptions::default())
+    }
+
+    /// Same as `fail`, with options such as a token of its own
+    pub fn fail_with(&self, check_id: &str, note: Option<&str>, options: &WriteOptions) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.ttl_update("fail", check_id, note, options)
+    }
+
+    fn ttl_update(&self, action: &str, check_id: &str, note: Option<&str>, options: &WriteOptions) -> Box<dyn Future<Item = (), Error = Error>> {
+        let mut uri = format!("/v1/agent/check/{}/", action);
+        uri.push_str(&escape::segment(check_id));
+        if let Some(note) = note {
+            uri.push_str("?note=");
+            uri.push_str(&escape::query(note));
+        }
+        unit_response(self.client.request_empty(Method::Put, &uri, options.token.as_deref()))
+    }
+
+    /// Sets the status and output of a TTL check and resets its timer.
+    ///
+    /// Unlike `pass`/`warn`/`fail` the output is sent in the body, so it may
+    /// be large.
+    pub fn update_check(&self, check_id: &str, status: CheckStatus, output: &str) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.update_check_with(check_id, status, output, &WriteOptions::default())
+    }
+
+    /// Same as `update_check`, with options such as a token of its own
+    pub fn update_check_with(&self, check_id: &str, status: CheckStatus, output: &str, options: &WriteOptions) -> Box<dyn Future<Item = (), Error = Error>> {
+        #[derive(Serialize)]
+        struct Update<'a> {
+            #[serde(rename = "Status")]
+            status: CheckStatus,
+            #[serde(rename = "Output")]
+            output: &'a str,
+        }
+
+        let mut uri: String = "/v1/agent/check/update/".into();
+        uri.push_str(&escape::segment(check_id));
+        unit_response(self.client.request_json(Method::Put, &uri, options.token.as_deref(), Update { status, output }))
+    }
 }
 
 impl<'a> KV<'a> {
-    pub fn put(&self, path: &str, data: Vec<u8>) -> Box<Future<Item = bool, Error = Error>> {
-        use hyper::header::{ContentType};
+    /// Fetches a single key, returns `None` if it does not exist
+    pub fn get(&self, key: &str) -> Box<dyn Future<Item = Option<KVPair>, Error = Error>> {
+        Box::new(self.get_with(key, &QueryOptions::default()).map(|(pair, _)| pair))
+    }
+
+    /// Same as `get`, but allows blocking queries
+    pub fn get_with(&self, key: &str, options: &QueryOptions) -> Box<dyn Future<Item = (Option<KVPair>, QueryMeta), Error = Error>> {
         let mut uri: String = "/v1/kv/".into();
-        uri.push_str(path);
-        Box::new(self.client.request(Method::Put, &uri, ContentType::octet_stream(), data)
-        .and_then(|resp| {
-            let status = resp.status();
-            resp.body().concat2().map(move |body| (status, body))
-        })
-        .map_err(|e| e.into())
-        .and_then(|(status, body)| {
-            if status.is_success() {
-                if body.starts_with(b"true") {
-                    return Ok(true);
-                }
-                if body.starts_with(b"false") {
-                    return Ok(false);
-                }
-            }
-            Err(Error::Consul(String::from_utf8_lossy(&body).to_string()))
-        }))
+        uri.push_str(&escape::key(key));
+        Box::new(self.client.get_json::<Vec<KVPair>>(&uri, options)
+        .map(|(pairs, meta)| (pairs.and_then(|mut pairs| pairs.pop()), meta)))
+    }
+
+    /// Fetches all the entries under `prefix`
+    pub fn list(&self, prefix: &str) -> Box<dyn Future<Item = Vec<KVPair>, Error = Error>> {
+        Box::new(self.list_with(prefix, &QueryOptions::default()).map(|(pairs, _)| pairs))
+    }
+
+    /// Same as `list`, but allows blocking queries
+    pub fn list_with(&self, prefix: &str, options: &QueryOptions) -> Box<dyn Future<Item = (Vec<KVPair>, QueryMeta), Error = Error>> {
+        let mut uri: String = "/v1/kv/".into();
+        uri.push_str(&escape::key(prefix));
+        uri.push_str("?recurse");
+        Box::new(self.client.get_json(&uri, options)
+        .map(|(pairs, meta)| (pairs.unwrap_or_default(), meta)))
+    }
+
+    /// Lists the keys under `prefix` without their values.
+    ///
+    /// With a `separator` only keys up to the next occurrence of it are
+    /// returned, like a directory listing.
+    pub fn keys(&self, prefix: &str, separator: Option<&str>) -> Box<dyn Future<Item = Vec<String>, Error = Error>> {
+        Box::new(self.keys_with(prefix, separator, &QueryOptions::default()).map(|(keys, _)| keys))
+    }
+
+    /// Same as `keys`, but allows blocking queries
+    pub fn keys_with(&self, prefix: &str, separator: Option<&str>, options: &QueryOptions) -> Box<dyn Future<Item = (Vec<String>, QueryMeta), Error = Error>> {
+        let mut uri: String = "/v1/kv/".into();
+        uri.push_str(&escape::key(prefix));
+        uri.push_str("?keys");
+        if let Some(separator) = separator {
+            uri.push_str("&separator=");
+            uri.push_str(&escape::query(separator));
+        }
+        Box::new(self.client.get_json(&uri, options)
+        .map(|(keys, meta)| (keys.unwrap_or_default(), meta)))
+    }
+
+    /// Watches a single key, yielding it every time it changes
+    pub fn watch(&self, key: &str, options: &QueryOptions) -> Watch<Option<KVPair>> {
+        let client = self.client.clone();
+        let key = key.to_string();
+        Watch::new(self.client.handle(), options.clone(), move |options| client.kv().get_with(&key, options))
+    }
+
+    /// Watches all the entries under `prefix`, yielding them every time
+    /// any of them changes
+    pub fn watch_prefix(&self, prefix: &str, options: &QueryOptions) -> Watch<Vec<KVPair>> {
+        let client = self.client.clone();
+        let prefix = prefix.to_string();
+        Watch::new(self.client.handle(), options.clone(), move |options| client.kv().list_with(&prefix, options))
+    }
+
+    pub fn put(&self, path: &str, data: Vec<u8>) -> Box<dyn Future<Item = bool, Error = Error>> {
+        self.put_with(path, data, &PutOptions::default())
+    }
+
+    /// Writes a key with check-and-set, flags or lock semantics.
+    ///
+    /// Resolves to `false` when the write was refused, e.g. the `cas` index
+    /// did not match or the lock is held by another session.
+    pub fn put_with(&self, path: &str, data: Vec<u8>, options: &PutOptions) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let mut uri: String = "/v1/kv/".into();
+        uri.push_str(&escape::key(path));
+        append_query(&mut uri, options.params());
+        let body = Some((ContentType::octet_stream(), data));
+        bool_response(self.client.send(Method::Put, &uri, options.token.as_deref(), body))
+    }
+
+    /// Deletes a single key
+    pub fn delete(&self, key: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        self.delete_with(key, &WriteOptions::default())
+    }
+
+    /// Same as `delete`, with options such as a token of its own
+    pub fn delete_with(&self, key: &str, options: &WriteOptions) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let mut uri: String = "/v1/kv/".into();
+        uri.push_str(&escape::key(key));
+        bool_response(self.client.request_empty(Method::Delete, &uri, options.token.as_deref()))
+    }
+
+    /// Deletes every key under `prefix`
+    pub fn delete_tree(&self, prefix: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        self.delete_tree_with(prefix, &WriteOptions::default())
+    }
+
+    /// Same as `delete_tree`, with options such as a token of its own
+    pub fn delete_tree_with(&self, prefix: &str, options: &WriteOptions) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let mut uri: String = "/v1/kv/".into();
+        uri.push_str(&escape::key(prefix));
+        uri.push_str("?recurse");
+        bool_response(self.client.request_empty(Method::Delete, &uri, options.token.as_deref()))
+    }
+
+    /// Deletes a key only if it has not been modified since `modify_index`,
+    /// resolves to `false` otherwise
+    pub fn delete_cas(&self, key: &str, modify_index: u64) -> Box<dyn Future<Item = bool, Error = Error>> {
+        self.delete_cas_with(key, modify_index, &WriteOptions::default())
+    }
+
+    /// Same as `delete_cas`, with options such as a token of its own
+    pub fn delete_cas_with(&self, key: &str, modify_index: u64, options: &WriteOptions) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let mut uri: String = "/v1/kv/".into();
+        uri.push_str(&escape::key(key));
+        uri.push_str(&format!("?cas={}", modify_index));
+        bool_response(self.client.request_empty(Method::Delete, &uri, options.token.as_deref()))
     }
 }
 
+/// Decodes a JSON response, a 404 yields `None`
+fn json_response<T: DeserializeOwned + 'static>(response: ResponseFuture) -> Box<dyn Future<Item = (Option<T>, QueryMeta), Error = Error>> {
+    Box::new(response
+    .and_then(|resp| {
+        let status = resp.status();
+        let meta = QueryMeta::from_headers(resp.headers());
+        resp.body().concat2().map(move |body| (status, meta, body)).map_err(Error::from)
+    })
+    .and_then(|(status, meta, body)| {
+        if status == StatusCode::NotFound {
+            return Ok((None, meta));
+        }
+        if status.is_success() {
+            return Ok((Some(serde_json::from_slice(&body)?), meta));
+        }
+        Err(Error::from_status(status, &body))
+    }))
+}
+
+/// Waits for a write which answers with an empty body
+fn unit_response(response: ResponseFuture) -> Box<dyn Future<Item = (), Error = Error>> {
+    Box::new(response
+    .and_then(|resp| {
+        let status = resp.status();
+        resp.body().concat2().map(move |body| (status, body)).map_err(Error::from)
+    })
+    .and_then(|(status, body)| {
+        if status.is_success() {
+            return Ok(());
+        }
+        Err(Error::from_status(status, &body))
+    }))
+}
+
+/// Decodes the `true`/`false` body consul answers KV writes with
+fn bool_response(response: ResponseFuture) -> Box<dyn Future<Item = bool, Error = Error>> {
+    Box::new(response
+    .and_then(|resp| {
+        let status = resp.status();
+        resp.body().concat2().map(move |body| (status, body)).map_err(Error::from)
+    })
+    .and_then(|(status, body)| {
+        if status.is_success() {
+            if body.starts_with(b"true") {
+                return Ok(true);
+            }
+            if body.starts_with(b"false") {
+                return Ok(false);
+            }
+        }
+        Err(Error::from_status(status, &body))
+    }))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Client, RegisterService, Check};
+    use super::{Client, Error, RegisterService, RegisterCheck, Check, CheckStatus, PutOptions, QueryOptions, WriteOptions};
+    use std::{env, fs, process};
+    use std::time::Duration;
     use tokio_core::reactor::Core;
+    use mock::{self, Reply};
+
     #[test]
+    #[ignore = "needs a consul agent on 127.0.0.1:8500"]
     fn it_works() {
         let mut core = Core::new().unwrap();
         let client = Client::new(&core.handle(), "http://127.0.0.1:8500").unwrap();
         let sr = RegisterService {
-            ID: "hello".to_string(),
-            Name: "test".to_string(),
-            Tags: vec![],
-            Port: 9999,
-            Address: "127.0.0.1".to_string(),
-            Check: Some(Check {
+            id: "hello".to_string(),
+            name: "test".to_string(),
+            tags: vec![],
+            port: 9999,
+            address: "127.0.0.1".to_string(),
+            check: Some(Check {
                 http: Some("http://127.0.0.1:9999/health".into()),
                 interval: "1s".into(),
                 script: None,
                 ttl: None,
-                DeregisterCriticalServiceAfter: "24h".into(),
+                deregister_critical_service_after: "24h".into(),
             })
         };
-        let res = core.run(client.agent().register(sr)).unwrap();
-        println!("{:?}", res);
+        core.run(client.agent().register(sr)).unwrap();
     }
 
     #[test]
+    #[ignore = "needs a consul agent on 127.0.0.1:8500"]
     fn key_value() {
         let mut core = Core::new().unwrap();
         let client = Client::new(&core.handle(), "http://127.0.0.1:8500").unwrap();
-        let res = core.run(client.kv().put("hello/world", vec![1,2,3,4])).unwrap();
-        println!("{:?}", res);
+        assert!(core.run(client.kv().put("hello/world", vec![1,2,3,4])).unwrap());
+    }
+
+    #[test]
+    fn kv_get() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"[{"LockIndex":1,"Key":"hello/world","Flags":42,"Value":"AQIDBA==","CreateIndex":10,"ModifyIndex":12,"Session":"adf4238a-882b-9ddc-4a9d-5b6758e4159e"}]"#),
+            Reply::new(404, ""),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let pair = core.run(client.kv().get("hello/world")).unwrap().unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/hello/world");
+        assert_eq!(pair.key, "hello/world");
+        assert_eq!(pair.value, vec![1, 2, 3, 4]);
+        assert_eq!(pair.flags, 42);
+        assert_eq!(pair.lock_index, 1);
+        assert_eq!(pair.create_index, 10);
+        assert_eq!(pair.modify_index, 12);
+        assert_eq!(pair.session, Some("adf4238a-882b-9ddc-4a9d-5b6758e4159e".into()));
+
+        let missing = core.run(client.kv().get("missing")).unwrap();
+        assert!(missing.is_none());
+    }
+
+    #[test]
+    fn kv_list_and_keys() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"[{"LockIndex":0,"Key":"tenant/a","Flags":0,"Value":"YQ==","CreateIndex":5,"ModifyIndex":5},
+                               {"LockIndex":0,"Key":"tenant/b","Flags":0,"Value":null,"CreateIndex":6,"ModifyIndex":7}]"#),
+            Reply::new(200, r#"["tenant/a","tenant/sub/"]"#),
+            Reply::new(404, ""),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let pairs = core.run(client.kv().list("tenant/")).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/tenant/?recurse");
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[0].value, b"a");
+        assert!(pairs[1].value.is_empty());
+
+        let keys = core.run(client.kv().keys("tenant/", Some("/"))).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/tenant/?keys&separator=/");
+        assert_eq!(keys, vec!["tenant/a".to_string(), "tenant/sub/".to_string()]);
+
+        let keys = core.run(client.kv().keys("missing/", None)).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/missing/?keys");
+        assert!(keys.is_empty());
+    }
+
+    #[test]
+    fn kv_delete() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, "true"),
+            Reply::new(200, "true"),
+            Reply::new(200, "false"),
+            Reply::new(500, "rpc error"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        assert!(core.run(client.kv().delete("hello/world")).unwrap());
+        let req = requests.recv().unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("DELETE", "/v1/kv/hello/world"));
+
+        assert!(core.run(client.kv().delete_tree("hello/")).unwrap());
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/hello/?recurse");
+
+        assert!(!core.run(client.kv().delete_cas("hello/world", 42)).unwrap());
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/hello/world?cas=42");
+
+        assert!(core.run(client.kv().delete("hello/world")).is_err());
+    }
+
+    #[test]
+    fn errors_by_status() {
+        let (url, _requests) = mock::serve(vec![
+            Reply::new(403, "ACL not found"),
+            Reply::new(429, "rate limit exceeded"),
+            Reply::new(500, "rpc error\n"),
+            Reply::new(404, ""),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        match core.run(client.kv().get("hello")) {
+            Err(Error::PermissionDenied(ref body)) if body == "ACL not found" => {}
+            other => panic!("expected permission denied, got {:?}", other),
+        }
+        match core.run(client.kv().delete("hello")) {
+            Err(Error::RateLimited(_)) => {}
+            other => panic!("expected rate limiting, got {:?}", other),
+        }
+        match core.run(client.kv().put("hello", Vec::new())) {
+            Err(Error::ServerError { status: 500, ref body }) if body == "rpc error" => {}
+            other => panic!("expected a server error, got {:?}", other),
+        }
+        // a missing key is an answer for reads only
+        match core.run(client.agent().deregister("web")) {
+            Err(e @ Error::NotFound(_)) => assert_eq!(e.to_string(), "not found: "),
+            other => panic!("expected not found, got {:?}", other),
+        }
+
+        match Client::new(&core.handle(), "http://[::1") {
+            Err(Error::InvalidUri(_)) => {}
+            _ => panic!("expected an invalid URI"),
+        }
+    }
+
+    #[test]
+    fn kv_put_with_options() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, "true"),
+            Reply::new(200, "false"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let options = PutOptions { cas: Some(0), flags: Some(7), ..Default::default() };
+        assert!(core.run(client.kv().put_with("service/leader", b"me".to_vec(), &options)).unwrap());
+        let req = requests.recv().unwrap();
+        assert_eq!(req.path, "/v1/kv/service/leader?cas=0&flags=7");
+        assert_eq!(req.body, b"me");
+
+        let options = PutOptions { acquire: Some("4ca8e74b".into()), ..Default::default() };
+        assert!(!core.run(client.kv().put_with("service/leader", vec![], &options)).unwrap());
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/service/leader?acquire=4ca8e74b");
+    }
+
+    #[test]
+    fn acl_tokens() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, "{}"),
+            Reply::new(404, ""),
+            Reply::new(200, "true"),
+            Reply::new(200, "{}"),
+            Reply::new(200, "true"),
+            Reply::new(200, ""),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap().with_token("default-token");
+
+        core.run(client.agent().services()).unwrap();
+        assert_eq!(requests.recv().unwrap().header("X-Consul-Token"), Some("default-token"));
+
+        let options = QueryOptions { token: Some("read-token".into()), ..Default::default() };
+        core.run(client.kv().get_with("hello", &options)).unwrap();
+        assert_eq!(requests.recv().unwrap().header("X-Consul-Token"), Some("read-token"));
+
+        let options = PutOptions { token: Some("write-token".into()), ..Default::default() };
+        core.run(client.kv().put_with("hello", vec![], &options)).unwrap();
+        assert_eq!(requests.recv().unwrap().header("X-Consul-Token"), Some("write-token"));
+
+        let options = QueryOptions { token: Some("agent-token".into()), ..Default::default() };
+        core.run(client.agent().services_with(&options)).unwrap();
+        assert_eq!(requests.recv().unwrap().header("X-Consul-Token"), Some("agent-token"));
+
+        let options = WriteOptions { token: Some("write-token".into()) };
+        core.run(client.kv().delete_with("hello", &options)).unwrap();
+        assert_eq!(requests.recv().unwrap().header("X-Consul-Token"), Some("write-token"));
+        core.run(client.agent().deregister_with("web", &options)).unwrap();
+        assert_eq!(requests.recv().unwrap().header("X-Consul-Token"), Some("write-token"));
+        core.run(client.acl().policy_delete_with("p1", &options)).unwrap();
+        let req = requests.recv().unwrap();
+        assert_eq!((req.path.as_str(), req.header("X-Consul-Token")), ("/v1/acl/policy/p1", Some("write-token")));
+    }
+
+    #[test]
+    fn client_from_env() {
+        let core = Core::new().unwrap();
+        let token_file = env::temp_dir().join(format!("consul-token-{}", process::id()));
+        fs::write(&token_file, "file-token\n").unwrap();
+
+        env::set_var("CONSUL_HTTP_ADDR", "consul.local:8501");
+        env::set_var("CONSUL_HTTP_TOKEN", "env-token");
+        let client = Client::from_env(&core.handle()).unwrap();
+        assert_eq!(client.token().as_deref(), Some("env-token"));
+        assert_eq!(client.uri("/v1/agent/self").unwrap(), "http://consul.local:8501/v1/agent/self");
+
+        env::set_var("CONSUL_HTTP_TOKEN_FILE", &token_file);
+        let client = Client::from_env(&core.handle()).unwrap();
+        assert_eq!(client.token().as_deref(), Some("file-token"));
+
+        env::set_var("CONSUL_HTTP_SSL", "true");
+        let client = Client::from_env(&core.handle()).unwrap();
+        assert_eq!(client.uri("/v1/agent/self").unwrap(), "https://consul.local:8501/v1/agent/self");
+
+        env::set_var("CONSUL_CACERT", "/nonexistent/ca.pem");
+        match Client::from_env(&core.handle()) {
+            Err(Error::Io(_)) if cfg!(feature = "tls") => {}
+            Ok(_) if !cfg!(feature = "tls") => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+
+        env::remove_var("CONSUL_HTTP_ADDR");
+        env::remove_var("CONSUL_HTTP_TOKEN");
+        env::remove_var("CONSUL_HTTP_TOKEN_FILE");
+        env::remove_var("CONSUL_HTTP_SSL");
+        env::remove_var("CONSUL_CACERT");
+        fs::remove_file(&token_file).unwrap();
+    }
+
+    #[test]
+    fn builder_hides_token() {
+        let builder = Client::builder("http://127.0.0.1:8500").token("secret");
+        let debug = format!("{:?}", builder);
+        assert!(debug.contains("127.0.0.1:8500"));
+        assert!(!debug.contains("secret"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn unix_socket() {
+        let (url, requests) = mock::serve_unix(vec![
+            Reply::new(200, r#"["a"]"#),
+            Reply::new(200, "true"),
+        ]);
+        assert!(url.starts_with("unix:///"));
+        let mut core = Core::new().unwrap();
+        let client = Client::builder(&url).token("secret").build(&core.handle()).unwrap();
+
+        assert_eq!(core.run(client.kv().keys("app/", None)).unwrap(), vec!["a"]);
+        let req = requests.recv().unwrap();
+        assert_eq!(req.path, "/v1/kv/app/?keys");
+        assert_eq!(req.header("Host"), Some("localhost"));
+        assert_eq!(req.header("X-Consul-Token"), Some("secret"));
+
+        assert!(core.run(client.kv().put("app/a", b"1".to_vec())).unwrap());
+        let req = requests.recv().unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("PUT", "/v1/kv/app/a"));
+        assert_eq!(req.body, b"1".to_vec());
+
+        let client = Client::new(&core.handle(), "unix:///nonexistent/consul_http.sock").unwrap();
+        assert!(core.run(client.kv().keys("app/", None)).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn https() {
+        let tls = |file| format!("{}/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), file);
+        let (url, requests) = mock::serve_tls(vec![
+            Reply::new(200, r#"["a"]"#),
+            Reply::new(200, r#"["b"]"#),
+        ]);
+        let mut core = Core::new().unwrap();
+
+        // the certificate is for consul.test, not the address in the url
+        let client = Client::builder(&url)
+            .ca_cert(&tls("ca.pem"))
+            .client_cert(&tls("client.pem"), &tls("client.key"))
+            .tls_server_name("consul.test")
+            .build(&core.handle()).unwrap();
+        assert_eq!(core.run(client.kv().keys("", None)).unwrap(), vec!["a"]);
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/?keys");
+
+        let client = Client::builder(&url).ca_cert(&tls("ca.pem")).build(&core.handle()).unwrap();
+        assert!(core.run(client.kv().keys("", None)).is_err());
+        let client = Client::new(&core.handle(), &url).unwrap();
+        assert!(core.run(client.kv().keys("", None)).is_err());
+
+        let client = Client::builder(&url).insecure_skip_verify(true).build(&core.handle()).unwrap();
+        assert_eq!(core.run(client.kv().keys("", None)).unwrap(), vec!["b"]);
+
+        // the key is not PKCS#8
+        match Client::builder(&url).client_cert(&tls("client.pem"), &tls("client.pem")).build(&core.handle()) {
+            Err(Error::Tls(_)) => {}
+            other => panic!("expected a TLS error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn escapes_paths() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, "true"),
+            Reply::new(200, ""),
+            Reply::new(200, "[]"),
+            Reply::new(200, "true"),
+            Reply::new(200, "[]"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        assert!(core.run(client.kv().put("app config/50% off?#1", b"x".to_vec())).unwrap());
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/app%20config/50%25%20off%3F%231");
+
+        core.run(client.agent().deregister("web/1")).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/agent/service/deregister/web%2F1");
+
+        core.run(client.kv().keys("a b/", Some("/"))).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/a%20b/?keys&separator=/");
+
+        let options = PutOptions { acquire: Some("s1&release=s2".into()), ..Default::default() };
+        assert!(core.run(client.kv().put_with("lock", b"x".to_vec(), &options)).unwrap());
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/lock?acquire=s1%26release%3Ds2");
+
+        let options = QueryOptions { datacenter: Some("eu west".into()), ..Default::default() };
+        core.run(client.kv().keys_with("a", None, &options)).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/a?keys&dc=eu%20west");
+    }
+
+    #[test]
+    fn kv_blocking_query() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"[{"LockIndex":0,"Key":"tenant/a","Flags":0,"Value":"YQ==","CreateIndex":5,"ModifyIndex":13}]"#)
+                .header("X-Consul-Index", "13")
+                .header("X-Consul-KnownLeader", "true")
+                .header("X-Consul-LastContact", "25"),
+            Reply::new(404, "").header("X-Consul-Index", "14"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let options = QueryOptions {
+            index: Some(12),
+            wait: Some(Duration::from_secs(30)),
+            datacenter: Some("dc2".into()),
+            ..Default::default()
+        };
+        let (pairs, meta) = core.run(client.kv().list_with("tenant/", &options)).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/tenant/?recurse&dc=dc2&index=12&wait=30000ms");
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(meta.last_index, 13);
+        assert!(meta.known_leader);
+        assert_eq!(meta.last_contact, Duration::from_millis(25));
+
+        let options = QueryOptions { index: Some(meta.last_index), allow_stale: true, ..Default::default() };
+        let (pair, meta) = core.run(client.kv().get_with("tenant/a", &options)).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/tenant/a?stale&index=13");
+        assert!(pair.is_none());
+        assert_eq!(meta.last_index, 14);
+        assert!(!meta.known_leader);
+    }
+
+    #[test]
+    fn agent_services() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"redis":{"ID":"redis","Service":"redis","Tags":[],"Meta":{"version":"4.0"},"Port":8000,"Address":""}}"#),
+            Reply::new(200, r#"{"ID":"redis","Service":"redis","Tags":null,"Port":8000,"Address":"10.0.0.1"}"#),
+            Reply::new(404, "unknown service ID: missing"),
+            Reply::new(200, ""),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let services = core.run(client.agent().services()).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/agent/services");
+        assert_eq!(services["redis"].port, 8000);
+        assert_eq!(services["redis"].meta.as_ref().unwrap()["version"], "4.0");
+
+        let service = core.run(client.agent().service("redis")).unwrap().unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/agent/service/redis");
+        assert_eq!(service.address, "10.0.0.1");
+
+        assert!(core.run(client.agent().service("missing")).unwrap().is_none());
+        requests.recv().unwrap();
+
+        core.run(client.agent().deregister("redis")).unwrap();
+        let req = requests.recv().unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("PUT", "/v1/agent/service/deregister/redis"));
+    }
+
+    #[test]
+    fn agent_checks() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, ""),
+            Reply::new(200, r#"{"worker-ttl":{"Node":"foobar","CheckID":"worker-ttl","Name":"Worker TTL","Status":"critical",
+                                "Notes":"","Output":"TTL expired","ServiceID":"","ServiceName":""}}"#),
+            Reply::new(200, ""),
+            Reply::new(200, ""),
+            Reply::new(200, ""),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let check = RegisterCheck {
+            id: Some("worker-ttl".into()),
+            name: "Worker TTL".into(),
+            service_id: None,
+            notes: None,
+            status: Some(CheckStatus::Passing),
+            check: Check {
+                ttl: Some("15s".into()),
+                interval: String::new(),
+                http: None,
+                script: None,
+                deregister_critical_service_after: "1h".into(),
+            },
+        };
+        core.run(client.agent().register_check(check)).unwrap();
+        let req = requests.recv().unwrap();
+        assert_eq!(req.path, "/v1/agent/check/register");
+        let body: ::serde_json::Value = ::serde_json::from_slice(&req.body).unwrap();
+        assert_eq!(body["ID"], "worker-ttl");
+        assert_eq!(body["Status"], "passing");
+        assert_eq!(body["ttl"], "15s");
+        assert!(body.get("interval").is_none());
+        assert_eq!(body["DeregisterCriticalServiceAfter"], "1h");
+
+        let checks = core.run(client.agent().checks()).unwrap();
+        requests.recv().unwrap();
+        assert_eq!(checks["worker-ttl"].status, CheckStatus::Critical);
+
+        core.run(client.agent().pass("worker-ttl", Some("all good & well"))).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/agent/check/pass/worker-ttl?note=all%20good%20%26%20well");
+
+        core.run(client.agent().update_check("worker-ttl", CheckStatus::Warning, "queue is backing up")).unwrap();
+        let req = requests.recv().unwrap();
+        assert_eq!(req.path, "/v1/agent/check/update/worker-ttl");
+        assert_eq!(req.body, br#"{"Status":"warning","Output":"queue is backing up"}"#.to_vec());
+
+        core.run(client.agent().deregister_check("worker-ttl")).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/agent/check/deregister/worker-ttl");
     }
 }
@@ -0,0 +1,325 @@
+//! Distributed lock on top of KV and sessions
+
+use std::time::Duration;
+
+use futures::{future, Future, Stream};
+use futures::future::{Loop, Shared};
+use futures::sync::oneshot;
+use tokio_core::reactor::Timeout;
+
+use {Client, CreateSession, Error, PutOptions, QueryOptions, SessionBehavior, SessionKeeper, Watch};
+
+/// Flags set on lock keys, the same value the Go client uses so that both
+/// recognize each other's locks
+pub const LOCK_FLAG_VALUE: u64 = 0x2ddc_cbc0_58a5_0c18;
+
+/// Configuration of a `Lock`
+#[derive(Debug, Clone)]
+pub struct LockOptions {
+    /// Key the lock lives at
+    pub key: String,
+    /// Value stored in the key while the lock is held
+    pub value: Vec<u8>,
+    /// Name of the session created to hold the lock
+    pub session_name: String,
+    /// TTL of the session created to hold the lock
+    pub session_ttl: String,
+    /// How long a single blocking query waits for the holder to let go
+    pub lock_wait: Duration,
+    /// Delay before retrying a refused acquire, which happens while the
+    /// lock-delay of the previous holder's session is in effect
+    pub retry_delay: Duration,
+    /// Failed queries tolerated in a row while the lock is held before it
+    /// is considered lost
+    pub monitor_retries: usize,
+}
+
+impl LockOptions {
+    /// Options matching the Go client's defaults
+    pub fn new(key: &str) -> Self {
+        LockOptions {
+            key: key.to_string(),
+            value: Vec::new(),
+            session_name: "Consul API Lock".into(),
+            session_ttl: "15s".into(),
+            lock_wait: Duration::from_secs(15),
+            retry_delay: Duration::from_secs(5),
+            monitor_retries: 0,
+        }
+    }
+}
+
+/// Mutual exclusion between processes, compatible with the Go client's
+/// `api.Lock`
+pub struct Lock {
+    client: Client,
+    options: LockOptions,
+}
+
+impl Lock {
+    pub fn new(client: &Client, options: LockOptions) -> Self {
+        Lock { client: client.clone(), options }
+    }
+
+    /// Waits until the lock is acquired.
+    ///
+    /// A session is created for the lock and renewed while it is held.
+    pub fn acquire(&self) -> Box<dyn Future<Item = HeldLock, Error = Error>> {
+        let client = self.client.clone();
+        let options = self.options.clone();
+        let session = CreateSession {
+            name: Some(options.session_name.clone()),
+            ttl: Some(options.session_ttl.clone()),
+            behavior: Some(SessionBehavior::Release),
+            ..Default::default()
+        };
+        Box::new(SessionKeeper::create(&client, &session).and_then(move |keeper| {
+            let session = keeper.id().to_string();
+            let attempts = {
+                let client = client.clone();
+                let options = options.clone();
+                future::loop_fn(None, move |index| attempt(&client, &options, &session, index))
+            };
+            attempts.map(move |()| HeldLock::new(client, options, keeper))
+        }))
+    }
+}
+
+/// One round of trying to get the lock, continues with the index to block
+/// on when somebody else holds it
+fn attempt(client: &Client, options: &LockOptions, session: &str, index: Option<u64>) -> Box<dyn Future<Item = Loop<(), Option<u64>>, Error = Error>> {
+    let query = QueryOptions { index, wait: Some(options.lock_wait), ..Default::default() };
+    let client = client.clone();
+    let key = options.key.clone();
+    let value = options.value.clone();
+    let retry_delay = options.retry_delay;
+    let session = session.to_string();
+    Box::new(client.kv().get_with(&key, &query).and_then(move |(pair, meta)| -> Box<dyn Future<Item = _, Error = _>> {
+        if let Some(pair) = pair {
+            if pair.flags != LOCK_FLAG_VALUE {
+                return Box::new(future::err(Error::Conflict(format!("{} is not used as a lock", key))));
+            }
+            match pair.session {
+                Some(ref holder) if *holder == session => return Box::new(future::ok(Loop::Break(()))),
+                Some(_) => return Box::new(future::ok(Loop::Continue(Some(meta.last_index)))),
+                None => {}
+            }
+        }
+
+        let put = PutOptions { flags: Some(LOCK_FLAG_VALUE), acquire: Some(session), ..Default::default() };
+        Box::new(client.kv().put_with(&key, value, &put).and_then(move |acquired| -> Box<dyn Future<Item = _, Error = _>> {
+            if acquired {
+                return Box::new(future::ok(Loop::Break(())));
+            }
+            match Timeout::new(retry_delay, client.handle()) {
+                Ok(timeout) => Box::new(timeout.map(|()| Loop::Continue(None)).map_err(Error::from)),
+                Err(e) => Box::new(future::err(e.into())),
+            }
+        }))
+    }))
+}
+
+/// Spawns a task telling when something held with `keeper`'s session is
+/// gone: the session is lost, `watch` yields a result for which `taken`
+/// holds, or more than `retries` queries failed in a row. Nothing can be
+/// known while consul cannot be reached, so the holder must assume the
+/// worst.
+///
+/// Returns the `lost` signal and the sender stopping the task when dropped.
+pub(crate) fn watch_lost<T, F>(client: &Client, keeper: &SessionKeeper, watch: Watch<T>, retries: usize, mut taken: F) -> (Shared<oneshot::Receiver<()>>, oneshot::Sender<()>)
+    where T: 'static, F: FnMut(&T) -> bool + 'static
+{
+    let (lost, lost_rx) = oneshot::channel::<()>();
+    let (stop, stopped) = oneshot::channel::<()>();
+
+    let mut failures = 0;
+    let taken = watch
+        .then(Ok::<_, ()>)
+        .filter(move |result| match *result {
+            Ok(ref result) => {
+                failures = 0;
+                taken(result)
+            }
+            Err(_) => {
+                failures += 1;
+                failures > retries
+            }
+        })
+        .into_future()
+        .map_err(|_| ());
+    let task = taken.select2(keeper.lost())
+        .then(|_| Ok::<bool, ()>(true))
+        .select(stopped.then(|_| Ok(false)))
+        .map_err(|_| ())
+        .map(move |(taken, _)| {
+            if taken {
+                let _ = lost.send(());
+            }
+        });
+    client.handle().spawn(task);
+    (lost_rx.shared(), stop)
+}
+
+/// A lock held by this process.
+///
+/// Dropping it destroys the session, which makes consul release the lock.
+pub struct HeldLock {
+    client: Client,
+    options: LockOptions,
+    keeper: SessionKeeper,
+    lost: Shared<oneshot::Receiver<()>>,
+    _stop: oneshot::Sender<()>,
+}
+
+impl HeldLock {
+    fn new(client: Client, options: LockOptions, keeper: SessionKeeper) -> Self {
+        let session = keeper.id().to_string();
+        let query = QueryOptions { wait: Some(options.lock_wait), ..Default::default() };
+        let watch = client.kv().watch(&options.key, &query);
+        let (lost, stop) = watch_lost(&client, &keeper, watch, options.monitor_retries, move |pair| {
+            pair.as_ref().and_then(|pair| pair.session.as_ref()) != Some(&session)
+        });
+        HeldLock { client, options, keeper, lost, _stop: stop }
+    }
+
+    /// Session holding the lock
+    pub fn session(&self) -> &str {
+        self.keeper.id()
+    }
+
+    /// Resolves once the lock is no longer held, because the session was
+    /// invalidated or the key was taken away, or may no longer be held
+    /// because consul could not be reached for too long
+    pub fn lost(&self) -> Box<dyn Future<Item = (), Error = ()>> {
+        Box::new(self.lost.clone().then(|_| Ok(())))
+    }
+
+    /// Releases the lock and destroys its session
+    pub fn release(self) -> Box<dyn Future<Item = (), Error = Error>> {
+        let HeldLock { client, options, keeper, _stop, .. } = self;
+        drop(_stop);
+        let put = PutOptions {
+            flags: Some(LOCK_FLAG_VALUE),
+            release: Some(keeper.id().to_string()),
+            ..Default::default()
+        };
+        Box::new(client.kv().put_with(&options.key, options.value, &put)
+        .then(move |released| {
+            drop(keeper);
+            released.map(|_| ())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tokio_core::reactor::Core;
+
+    use mock::{self, KvEntry, Reply};
+    use {Client, LOCK_FLAG_VALUE};
+    use super::{Lock, LockOptions};
+
+    #[test]
+    fn acquire_and_lose() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"s1"}"#),
+            Reply::new(404, "").header("X-Consul-Index", "9"),
+            Reply::new(200, "true"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("locks/job").flags(LOCK_FLAG_VALUE).session("s1")])).header("X-Consul-Index", "10"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("locks/job").flags(LOCK_FLAG_VALUE)])).header("X-Consul-Index", "11"),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let lock = Lock::new(&client, LockOptions::new("locks/job"));
+        let held = core.run(lock.acquire()).unwrap();
+        assert_eq!(held.session(), "s1");
+        core.run(held.lost()).unwrap();
+
+        let paths: Vec<_> = requests.iter().take(5).map(|req| req.path).collect();
+        assert_eq!(paths, vec![
+            "/v1/session/create",
+            "/v1/kv/locks/job?wait=15000ms",
+            "/v1/kv/locks/job?flags=3304740253564472344&acquire=s1",
+            "/v1/kv/locks/job?wait=15000ms",
+            "/v1/kv/locks/job?index=10&wait=15000ms",
+        ]);
+
+        drop(held);
+        assert_eq!(mock::next_request(&mut core, &requests).path, "/v1/session/destroy/s1");
+    }
+
+    #[test]
+    fn lost_once_queries_fail() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"s1"}"#),
+            Reply::new(404, "").header("X-Consul-Index", "9"),
+            Reply::new(200, "true"),
+            Reply::new(500, "No cluster leader"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let held = core.run(Lock::new(&client, LockOptions::new("locks/job")).acquire()).unwrap();
+        core.run(held.lost()).unwrap();
+        assert_eq!(requests.iter().nth(3).unwrap().path, "/v1/kv/locks/job?wait=15000ms");
+    }
+
+    #[test]
+    fn lost_once_renewals_fail_for_a_ttl() {
+        let (url, _requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"s1"}"#),
+            Reply::new(404, "").header("X-Consul-Index", "9"),
+            Reply::new(200, "true"),
+            Reply::new(500, "No cluster leader"),
+            Reply::new(500, "No cluster leader"),
+            Reply::new(500, "No cluster leader"),
+            Reply::new(500, "No cluster leader"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let mut options = LockOptions::new("locks/job");
+        options.session_ttl = "200ms".into();
+        options.monitor_retries = 100;
+        let held = core.run(Lock::new(&client, options).acquire()).unwrap();
+        let started = Instant::now();
+        core.run(held.lost()).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn waits_for_holder_and_lock_delay() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"s1"}"#),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("locks/job").flags(LOCK_FLAG_VALUE).session("other")])).header("X-Consul-Index", "5"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("locks/job").flags(LOCK_FLAG_VALUE)])).header("X-Consul-Index", "6"),
+            Reply::new(200, "false"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("locks/job").flags(LOCK_FLAG_VALUE)])).header("X-Consul-Index", "6"),
+            Reply::new(200, "true"),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let mut options = LockOptions::new("locks/job");
+        options.retry_delay = Duration::from_millis(10);
+        let held = core.run(Lock::new(&client, options).acquire()).unwrap();
+
+        let paths: Vec<_> = requests.iter().take(6).map(|req| req.path).collect();
+        assert_eq!(paths, vec![
+            "/v1/session/create",
+            "/v1/kv/locks/job?wait=15000ms",
+            "/v1/kv/locks/job?index=5&wait=15000ms",
+            "/v1/kv/locks/job?flags=3304740253564472344&acquire=s1",
+            "/v1/kv/locks/job?wait=15000ms",
+            "/v1/kv/locks/job?flags=3304740253564472344&acquire=s1",
+        ]);
+
+        core.run(held.release()).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/kv/locks/job?flags=3304740253564472344&release=s1");
+    }
+}
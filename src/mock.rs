@@ -0,0 +1,215 @@
+//! A tiny HTTP server standing in for the consul agent in tests
+
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64;
+use tokio_core::reactor::Core;
+
+/// Canned response served for a single request
+pub struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn new(status: u16, body: &str) -> Self {
+        Reply { status, headers: Vec::new(), body: body.as_bytes().to_vec() }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+/// A key as the agent returns it, see `kv_list`
+pub struct KvEntry {
+    key: String,
+    flags: u64,
+    value: Option<Vec<u8>>,
+    session: Option<String>,
+    modify_index: u64,
+}
+
+impl KvEntry {
+    /// Entry without flags, value or session, modified at index 1
+    pub fn new(key: &str) -> Self {
+        KvEntry { key: key.into(), flags: 0, value: None, session: None, modify_index: 1 }
+    }
+
+    pub fn flags(mut self, flags: u64) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn value(mut self, value: &str) -> Self {
+        self.value = Some(value.as_bytes().to_vec());
+        self
+    }
+
+    pub fn session(mut self, session: &str) -> Self {
+        self.session = Some(session.into());
+        self
+    }
+
+    pub fn modify_index(mut self, index: u64) -> Self {
+        self.modify_index = index;
+        self
+    }
+
+    fn json(&self) -> String {
+        let value = self.value.as_ref().map(|value| format!(r#""{}""#, base64::encode(value))).unwrap_or_else(|| "null".into());
+        let session = self.session.as_ref().map(|session| format!(r#","Session":"{}""#, session)).unwrap_or_default();
+        format!(r#"{{"LockIndex":0,"Key":"{}","Flags":{},"Value":{},"CreateIndex":1,"ModifyIndex":{}{}}}"#,
+                self.key, self.flags, value, self.modify_index, session)
+    }
+}
+
+/// Body of a KV read returning `entries`
+pub fn kv_list(entries: &[KvEntry]) -> String {
+    let entries: Vec<_> = entries.iter().map(KvEntry::json).collect();
+    format!("[{}]", entries.join(","))
+}
+
+/// Request as seen by the server
+#[derive(Debug)]
+pub struct Recorded {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Recorded {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Serves `replies` in order, one connection per request.
+///
+/// Returns the base url to point the `Client` at and a channel of the
+/// requests received so far.
+pub fn serve(replies: Vec<Reply>) -> (String, Receiver<Recorded>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let rx = serve_on(replies, move || listener.accept().ok().map(|(stream, _)| stream));
+    (url, rx)
+}
+
+/// Same as `serve`, over TLS with the certificate in `testdata/tls`
+#[cfg(feature = "tls")]
+pub fn serve_tls(replies: Vec<Reply>) -> (String, Receiver<Recorded>) {
+    use native_tls::{Identity, TlsAcceptor};
+
+    let identity = Identity::from_pkcs8(include_bytes!("../testdata/tls/server.pem"),
+                                        include_bytes!("../testdata/tls/server.key")).unwrap();
+    let acceptor = TlsAcceptor::new(identity).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("https://{}", listener.local_addr().unwrap());
+    let rx = serve_on(replies, move || loop {
+        // a client refusing the certificate only costs its connection
+        let (stream, _) = listener.accept().ok()?;
+        if let Ok(stream) = acceptor.accept(stream) {
+            return Some(stream);
+        }
+    });
+    (url, rx)
+}
+
+/// Same as `serve`, over a Unix socket in the temporary directory
+#[cfg(unix)]
+pub fn serve_unix(replies: Vec<Reply>) -> (String, Receiver<Recorded>) {
+    use std::env;
+    use std::os::unix::net::UnixListener;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static SOCKETS: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir().join(format!("consul-mock-{}-{}.sock", process::id(), SOCKETS.fetch_add(1, Ordering::SeqCst)));
+    let _ = ::std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let url = format!("unix://{}", path.display());
+    let rx = serve_on(replies, move || listener.accept().ok().map(|(stream, _)| stream));
+    (url, rx)
+}
+
+/// Answers one request per connection returned by `accept`
+fn serve_on<S, A>(replies: Vec<Reply>, mut accept: A) -> Receiver<Recorded>
+    where S: Read + Write, A: FnMut() -> Option<S> + Send + 'static
+{
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for reply in replies {
+            let stream = match accept() {
+                Some(stream) => stream,
+                None => return,
+            };
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap_or("").to_string();
+            let path = parts.next().unwrap_or("").to_string();
+
+            let mut headers = Vec::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(pos) = line.find(':') {
+                    let name = line[..pos].trim().to_string();
+                    let value = line[pos + 1..].trim().to_string();
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.parse().unwrap();
+                    }
+                    headers.push((name, value));
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let mut stream = reader.into_inner();
+            let mut response = format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+                                       reply.status, reply.body.len());
+            for (name, value) in reply.headers {
+                response.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            response.push_str("\r\n");
+            let _ = stream.write_all(response.as_bytes());
+            let _ = stream.write_all(&reply.body);
+            let _ = stream.flush();
+
+            if tx.send(Recorded { method, path, headers, body }).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+/// Turns the reactor until the next request reaches the server, for
+/// requests made by tasks spawned in the background
+pub fn next_request(core: &mut Core, requests: &Receiver<Recorded>) -> Recorded {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        core.turn(Some(Duration::from_millis(10)));
+        if let Ok(req) = requests.try_recv() {
+            return req;
+        }
+    }
+    panic!("no request received");
+}
@@ -0,0 +1,367 @@
+//! Distributed semaphore on top of KV and sessions
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures::{future, Future};
+use futures::future::{Loop, Shared};
+use futures::sync::oneshot;
+use serde_json;
+
+use lock::watch_lost;
+use {Client, CreateSession, Error, KVPair, PutOptions, QueryOptions, SessionBehavior, SessionKeeper};
+
+/// Flags set on the semaphore keys, the same value the Go client uses so
+/// that both recognize each other's semaphores
+pub const SEMAPHORE_FLAG_VALUE: u64 = 0xe0f6_9a2b_aa41_4de0;
+
+/// Configuration of a `Semaphore`
+#[derive(Debug, Clone)]
+pub struct SemaphoreOptions {
+    /// Prefix the contender keys and the `.lock` document live under
+    pub prefix: String,
+    /// Number of holders allowed at the same time
+    pub limit: u32,
+    /// Value stored in this contender's key
+    pub value: Vec<u8>,
+    /// Name of the session created to hold the slot
+    pub session_name: String,
+    /// TTL of the session created to hold the slot
+    pub session_ttl: String,
+    /// How long a single blocking query waits for a slot to free up
+    pub wait: Duration,
+    /// Failed queries tolerated in a row while the slot is held before it
+    /// is considered lost
+    pub monitor_retries: usize,
+}
+
+impl SemaphoreOptions {
+    /// Options matching the Go client's defaults
+    pub fn new(prefix: &str, limit: u32) -> Self {
+        SemaphoreOptions {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            limit,
+            value: Vec::new(),
+            session_name: "Consul API Semaphore".into(),
+            session_ttl: "15s".into(),
+            wait: Duration::from_secs(15),
+            monitor_retries: 0,
+        }
+    }
+
+    fn lock_key(&self) -> String {
+        format!("{}/.lock", self.prefix)
+    }
+
+    fn contender_key(&self, session: &str) -> String {
+        format!("{}/{}", self.prefix, session)
+    }
+
+    fn list_prefix(&self) -> String {
+        format!("{}/", self.prefix)
+    }
+}
+
+/// Contents of the `.lock` key: the limit and the sessions holding a slot
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SemaphoreLock {
+    #[serde(rename = "Limit")]
+    limit: u32,
+    #[serde(rename = "Holders")]
+    holders: HashMap<String, bool>,
+}
+
+impl SemaphoreLock {
+    fn decode(pair: Option<&KVPair>, options: &SemaphoreOptions) -> Result<Self, Error> {
+        let pair = match pair {
+            Some(pair) => pair,
+            None => return Ok(SemaphoreLock { limit: options.limit, holders: HashMap::new() }),
+        };
+        if pair.flags != SEMAPHORE_FLAG_VALUE {
+            return Err(Error::Conflict(format!("{} is not used as a semaphore", pair.key)));
+        }
+        let lock: SemaphoreLock = if pair.value.is_empty() {
+            SemaphoreLock { limit: options.limit, holders: HashMap::new() }
+        } else {
+            serde_json::from_slice(&pair.value)?
+        };
+        if lock.limit != options.limit {
+            return Err(Error::Conflict(format!("semaphore limit conflict (lock: {}, local: {})", lock.limit, options.limit)));
+        }
+        Ok(lock)
+    }
+}
+
+/// Allows up to `limit` holders at a time, compatible with `consul lock -n`
+/// and the Go client's `api.Semaphore`.
+///
+/// Each contender acquires a key under the prefix with its session, the
+/// holders are recorded in the `.lock` document updated with check-and-set.
+pub struct Semaphore {
+    client: Client,
+    options: SemaphoreOptions,
+}
+
+impl Semaphore {
+    pub fn new(client: &Client, options: SemaphoreOptions) -> Self {
+        Semaphore { client: client.clone(), options }
+    }
+
+    /// Waits until a slot is acquired.
+    ///
+    /// A session is created for the slot and renewed while it is held. A
+    /// limit of 0 fails with `Error::InvalidOptions`.
+    pub fn acquire(&self) -> Box<dyn Future<Item = HeldSemaphore, Error = Error>> {
+        if self.options.limit == 0 {
+            return Box::new(future::err(Error::InvalidOptions("semaphore limit must be positive".into())));
+        }
+        let client = self.client.clone();
+        let options = self.options.clone();
+        let session = CreateSession {
+            name: Some(options.session_name.clone()),
+            ttl: Some(options.session_ttl.clone()),
+            // contender keys go away along with the session
+            behavior: Some(SessionBehavior::Delete),
+            ..Default::default()
+        };
+        Box::new(SessionKeeper::create(&client, &session).and_then(move |keeper| {
+            let session = keeper.id().to_string();
+            let put = PutOptions {
+                flags: Some(SEMAPHORE_FLAG_VALUE),
+                acquire: Some(session.clone()),
+                ..Default::default()
+            };
+            let contender = client.kv().put_with(&options.contender_key(&session), options.value.clone(), &put)
+            .and_then(|created| {
+                if created {
+                    return Ok(());
+                }
+                Err(Error::Conflict("failed to make contender entry".into()))
+            });
+            let attempts = {
+                let client = client.clone();
+                let options = options.clone();
+                future::loop_fn(None, move |index| attempt(&client, &options, &session, index))
+            };
+            contender.and_then(|()| attempts)
+            .map(move |()| HeldSemaphore::new(client, options, keeper))
+        }))
+    }
+}
+
+/// One round of trying to get a slot, continues with the index to block on
+/// while the semaphore is full
+fn attempt(client: &Client, options: &SemaphoreOptions, session: &str, index: Option<u64>) -> Box<dyn Future<Item = Loop<(), Option<u64>>, Error = Error>> {
+    let query = QueryOptions { index, wait: Some(options.wait), ..Default::default() };
+    let client = client.clone();
+    let options = options.clone();
+    let session = session.to_string();
+    Box::new(client.kv().list_with(&options.list_prefix(), &query).and_then(move |(pairs, meta)| -> Box<dyn Future<Item = _, Error = _>> {
+        let lock_key = options.lock_key();
+        let lock_pair = pairs.iter().find(|pair| pair.key == lock_key);
+        let mut lock = match SemaphoreLock::decode(lock_pair, &options) {
+            Ok(lock) => lock,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        // holders whose contender key is gone have lost their session
+        let alive: Vec<&str> = pairs.iter()
+            .filter(|pair| pair.key != lock_key && pair.flags == SEMAPHORE_FLAG_VALUE)
+            .filter_map(|pair| pair.session.as_deref())
+            .collect();
+        lock.holders.retain(|holder, _| alive.contains(&holder.as_str()));
+
+        if lock.holders.len() >= lock.limit as usize {
+            return Box::new(future::ok(Loop::Continue(Some(meta.last_index))));
+        }
+        lock.holders.insert(session, true);
+
+        let value = match serde_json::to_vec(&lock) {
+            Ok(value) => value,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        let put = PutOptions {
+            cas: Some(lock_pair.map(|pair| pair.modify_index).unwrap_or(0)),
+            flags: Some(SEMAPHORE_FLAG_VALUE),
+            ..Default::default()
+        };
+        let last_index = meta.last_index;
+        Box::new(client.kv().put_with(&lock_key, value, &put).map(move |updated| {
+            if updated {
+                return Loop::Break(());
+            }
+            Loop::Continue(Some(last_index))
+        }))
+    }))
+}
+
+/// A slot of a semaphore held by this process.
+///
+/// Dropping it destroys the session, which removes the contender key and
+/// lets others prune this holder.
+pub struct HeldSemaphore {
+    client: Client,
+    options: SemaphoreOptions,
+    keeper: SessionKeeper,
+    lost: Shared<oneshot::Receiver<()>>,
+    _stop: oneshot::Sender<()>,
+}
+
+impl HeldSemaphore {
+    fn new(client: Client, options: SemaphoreOptions, keeper: SessionKeeper) -> Self {
+        let session = keeper.id().to_string();
+        let lock_key = options.lock_key();
+        let query = QueryOptions { wait: Some(options.wait), ..Default::default() };
+        let watch = client.kv().watch_prefix(&options.list_prefix(), &query);
+        let (lost, stop) = watch_lost(&client, &keeper, watch, options.monitor_retries, move |pairs| {
+            let lock = pairs.iter().find(|pair| pair.key == lock_key)
+                .and_then(|pair| serde_json::from_slice::<SemaphoreLock>(&pair.value).ok());
+            !lock.map(|lock| lock.holders.contains_key(&session)).unwrap_or(false)
+        });
+        HeldSemaphore { client, options, keeper, lost, _stop: stop }
+    }
+
+    /// Session holding the slot
+    pub fn session(&self) -> &str {
+        self.keeper.id()
+    }
+
+    /// Resolves once the slot is no longer held, because the session was
+    /// invalidated or this holder was removed from the `.lock` document, or
+    /// may no longer be held because consul could not be reached for too
+    /// long
+    pub fn lost(&self) -> Box<dyn Future<Item = (), Error = ()>> {
+        Box::new(self.lost.clone().then(|_| Ok(())))
+    }
+
+    /// Gives the slot back, removes the contender key and destroys the
+    /// session
+    pub fn release(self) -> Box<dyn Future<Item = (), Error = Error>> {
+        let HeldSemaphore { client, options, keeper, _stop, .. } = self;
+        drop(_stop);
+        let session = keeper.id().to_string();
+        let contender_key = options.contender_key(&session);
+
+        let removed = {
+            let client = client.clone();
+            future::loop_fn((), move |()| remove_holder(&client, &options, &session))
+        };
+        Box::new(removed
+        .and_then(move |()| client.kv().delete(&contender_key))
+        .then(move |deleted| {
+            drop(keeper);
+            deleted.map(|_| ())
+        }))
+    }
+}
+
+/// Takes `session` out of the holders, repeating until the check-and-set
+/// goes through
+fn remove_holder(client: &Client, options: &SemaphoreOptions, session: &str) -> Box<dyn Future<Item = Loop<(), ()>, Error = Error>> {
+    let client = client.clone();
+    let options = options.clone();
+    let session = session.to_string();
+    let lock_key = options.lock_key();
+    Box::new(client.kv().get(&lock_key).and_then(move |pair| -> Box<dyn Future<Item = _, Error = _>> {
+        let mut lock = match SemaphoreLock::decode(pair.as_ref(), &options) {
+            Ok(lock) => lock,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let pair = match pair {
+            Some(ref pair) if lock.holders.remove(&session).is_some() => pair,
+            _ => return Box::new(future::ok(Loop::Break(()))),
+        };
+        let value = match serde_json::to_vec(&lock) {
+            Ok(value) => value,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        let put = PutOptions {
+            cas: Some(pair.modify_index),
+            flags: Some(SEMAPHORE_FLAG_VALUE),
+            ..Default::default()
+        };
+        Box::new(client.kv().put_with(&lock_key, value, &put).map(|updated| {
+            if updated {
+                return Loop::Break(());
+            }
+            Loop::Continue(())
+        }))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use tokio_core::reactor::Core;
+
+    use mock::{self, KvEntry, Reply};
+    use {Client, Error, SEMAPHORE_FLAG_VALUE};
+    use super::{Semaphore, SemaphoreLock, SemaphoreOptions};
+
+    #[test]
+    fn acquire_prunes_dead_holders() {
+        let lock = |holders: &str| KvEntry::new("jobs/.lock").flags(SEMAPHORE_FLAG_VALUE).value(&format!(r#"{{"Limit":2,"Holders":{{{}}}}}"#, holders)).modify_index(7);
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"s1"}"#),
+            Reply::new(200, "true"),
+            Reply::new(200, &mock::kv_list(&[
+                lock(r#""s0":true,"dead":true"#),
+                KvEntry::new("jobs/s0").flags(SEMAPHORE_FLAG_VALUE).value("").session("s0").modify_index(3),
+                KvEntry::new("jobs/s1").flags(SEMAPHORE_FLAG_VALUE).value("").session("s1").modify_index(8),
+            ])).header("X-Consul-Index", "20"),
+            Reply::new(200, "true"),
+            Reply::new(200, &mock::kv_list(&[lock(r#""s0":true,"s1":true"#)])).header("X-Consul-Index", "21"),
+            Reply::new(200, &mock::kv_list(&[lock(r#""s0":true"#)])).header("X-Consul-Index", "22"),
+            Reply::new(200, &mock::kv_list(&[lock(r#""s0":true,"s1":true"#).modify_index(23)])),
+            Reply::new(200, "true"),
+            Reply::new(200, "true"),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let held = core.run(Semaphore::new(&client, SemaphoreOptions::new("jobs/", 2)).acquire()).unwrap();
+        core.run(held.lost()).unwrap();
+
+        let reqs: Vec<_> = requests.iter().take(6).collect();
+        let paths: Vec<_> = reqs.iter().map(|req| req.path.as_str()).collect();
+        assert_eq!(paths, vec![
+            "/v1/session/create",
+            "/v1/kv/jobs/s1?flags=16210313421097356768&acquire=s1",
+            "/v1/kv/jobs/?recurse&wait=15000ms",
+            "/v1/kv/jobs/.lock?cas=7&flags=16210313421097356768",
+            "/v1/kv/jobs/?recurse&wait=15000ms",
+            "/v1/kv/jobs/?recurse&index=21&wait=15000ms",
+        ]);
+        let lock: SemaphoreLock = serde_json::from_slice(&reqs[3].body).unwrap();
+        assert_eq!(lock.limit, 2);
+        assert_eq!(lock.holders.len(), 2);
+        assert!(lock.holders.contains_key("s1"));
+        assert!(reqs[0].body.windows(8).any(|w| w == b"\"delete\""));
+
+        drop(reqs);
+        core.run(held.release()).unwrap();
+        let reqs: Vec<_> = requests.iter().take(3).collect();
+        let paths: Vec<_> = reqs.iter().map(|req| req.path.as_str()).collect();
+        assert_eq!(paths, vec![
+            "/v1/kv/jobs/.lock",
+            "/v1/kv/jobs/.lock?cas=23&flags=16210313421097356768",
+            "/v1/kv/jobs/s1",
+        ]);
+        assert_eq!(reqs[1].body, br#"{"Limit":2,"Holders":{"s0":true}}"#.to_vec());
+        assert_eq!(mock::next_request(&mut core, &requests).path, "/v1/session/destroy/s1");
+    }
+
+    #[test]
+    fn rejects_zero_limit() {
+        let (url, requests) = mock::serve(vec![]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        match core.run(Semaphore::new(&client, SemaphoreOptions::new("jobs/", 0)).acquire()) {
+            Err(Error::InvalidOptions(_)) => {}
+            other => panic!("expected invalid options, got {:?}", other.map(|held| held.session().to_string())),
+        }
+        assert!(requests.try_recv().is_err());
+    }
+}
@@ -0,0 +1,432 @@
+//! Session endpoint
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use futures::future::Shared;
+use futures::sync::oneshot;
+use hyper::Method;
+use tokio_core::reactor::Interval;
+
+use {bool_response, escape, json_response, Client, Error, QueryMeta, QueryOptions, WriteOptions};
+
+/// What happens to the locks held by a session once it is invalidated
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionBehavior {
+    /// The locks are released, the keys stay
+    Release,
+    /// The locked keys are deleted
+    Delete,
+}
+
+/// Service check a session is tied to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceCheck {
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "Namespace")]
+    pub namespace: Option<String>,
+}
+
+/// CreateSession describes a session to create, everything left out gets
+/// consul's defaults
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct CreateSession {
+    #[serde(skip_serializing_if = "Option::is_none", rename = "Name")]
+    pub name: Option<String>,
+    /// Node to create the session on, defaults to the agent's node
+    #[serde(skip_serializing_if = "Option::is_none", rename = "Node")]
+    pub node: Option<String>,
+    /// Session is invalidated unless renewed within this duration, e.g. "30s"
+    #[serde(skip_serializing_if = "Option::is_none", rename = "TTL")]
+    pub ttl: Option<String>,
+    /// Time a released lock can not be reacquired for, e.g. "15s"
+    #[serde(skip_serializing_if = "Option::is_none", rename = "LockDelay")]
+    pub lock_delay: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "Behavior")]
+    pub behavior: Option<SessionBehavior>,
+    /// Node checks which invalidate the session when critical, defaults to
+    /// `serfHealth`
+    #[serde(skip_serializing_if = "Option::is_none", rename = "NodeChecks")]
+    pub node_checks: Option<Vec<String>>,
+    /// Service checks which invalidate the session when critical
+    #[serde(skip_serializing_if = "Option::is_none", rename = "ServiceChecks")]
+    pub service_checks: Option<Vec<ServiceCheck>>,
+}
+
+/// SessionEntry is a session as known to consul
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionEntry {
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(default, rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Node")]
+    pub node: String,
+    /// Lock delay in nanoseconds
+    #[serde(default, rename = "LockDelay")]
+    pub lock_delay: u64,
+    #[serde(rename = "Behavior")]
+    pub behavior: SessionBehavior,
+    #[serde(default, rename = "TTL")]
+    pub ttl: String,
+    #[serde(default, rename = "NodeChecks")]
+    pub node_checks: Option<Vec<String>>,
+    #[serde(default, rename = "ServiceChecks")]
+    pub service_checks: Option<Vec<ServiceCheck>>,
+    #[serde(default, rename = "CreateIndex")]
+    pub create_index: u64,
+    #[serde(default, rename = "ModifyIndex")]
+    pub modify_index: u64,
+}
+
+/// Session endpoint
+pub struct Session<'a> {
+    client: &'a Client,
+}
+
+impl<'a> Session<'a> {
+    pub(crate) fn new(client: &'a Client) -> Self {
+        Session { client }
+    }
+
+    /// Creates a session, resolves to its ID
+    pub fn create(&self, session: &CreateSession) -> Box<dyn Future<Item = String, Error = Error>> {
+        self.create_with(session, &WriteOptions::default())
+    }
+
+    /// Same as `create`, with options such as a token of its own
+    pub fn create_with(&self, session: &CreateSession, options: &WriteOptions) -> Box<dyn Future<Item = String, Error = Error>> {
+        #[derive(Deserialize)]
+        struct Created {
+            #[serde(rename = "ID")]
+            id: String,
+        }
+
+        Box::new(json_response::<Created>(self.client.request_json(Method::Put, "/v1/session/create", options.token.as_deref(), session))
+        .and_then(|(created, _)| {
+            created.map(|created| created.id)
+                .ok_or_else(|| Error::NotFound("session create returned 404".into()))
+        }))
+    }
+
+    /// Invalidates a session, releasing or deleting its locks
+    pub fn destroy(&self, id: &str) -> Box<dyn Future<Item = bool, Error = Error>> {
+        self.destroy_with(id, &WriteOptions::default())
+    }
+
+    /// Same as `destroy`, with options such as a token of its own
+    pub fn destroy_with(&self, id: &str, options: &WriteOptions) -> Box<dyn Future<Item = bool, Error = Error>> {
+        let mut uri: String = "/v1/session/destroy/".into();
+        uri.push_str(&escape::segment(id));
+        bool_response(self.client.request_empty(Method::Put, &uri, options.token.as_deref()))
+    }
+
+    /// Resets the TTL of a session, resolves to `None` if the session is
+    /// already gone
+    pub fn renew(&self, id: &str) -> Box<dyn Future<Item = Option<SessionEntry>, Error = Error>> {
+        self.renew_with(id, &WriteOptions::default())
+    }
+
+    /// Same as `renew`, with options such as a token of its own
+    pub fn renew_with(&self, id: &str, options: &WriteOptions) -> Box<dyn Future<Item = Option<SessionEntry>, Error = Error>> {
+        let mut uri: String = "/v1/session/renew/".into();
+        uri.push_str(&escape::segment(id));
+        Box::new(json_response::<Vec<SessionEntry>>(self.client.request_empty(Method::Put, &uri, options.token.as_deref()))
+        .map(|(sessions, _)| sessions.and_then(|mut sessions| sessions.pop())))
+    }
+
+    /// Fetches a session, returns `None` if it does not exist
+    pub fn info(&self, id: &str) -> Box<dyn Future<Item = Option<SessionEntry>, Error = Error>> {
+        Box::new(self.info_with(id, &QueryOptions::default()).map(|(session, _)| session))
+    }
+
+    /// Same as `info`, but allows blocking queries
+    pub fn info_with(&self, id: &str, options: &QueryOptions) -> Box<dyn Future<Item = (Option<SessionEntry>, QueryMeta), Error = Error>> {
+        let mut uri: String = "/v1/session/info/".into();
+        uri.push_str(&escape::segment(id));
+        // unknown sessions come back as `null` or an empty list
+        Box::new(self.client.get_json::<Option<Vec<SessionEntry>>>(&uri, options)
+        .map(|(sessions, meta)| (sessions.and_then(|sessions| sessions).and_then(|mut sessions| sessions.pop()), meta)))
+    }
+
+    /// Lists the sessions belonging to a node
+    pub fn node(&self, node: &str) -> Box<dyn Future<Item = Vec<SessionEntry>, Error = Error>> {
+        Box::new(self.node_with(node, &QueryOptions::default()).map(|(sessions, _)| sessions))
+    }
+
+    /// Same as `node`, but allows blocking queries
+    pub fn node_with(&self, node: &str, options: &QueryOptions) -> Box<dyn Future<Item = (Vec<SessionEntry>, QueryMeta), Error = Error>> {
+        let mut uri: String = "/v1/session/node/".into();
+        uri.push_str(&escape::segment(node));
+        Box::new(self.client.get_json::<Option<Vec<SessionEntry>>>(&uri, options)
+        .map(|(sessions, meta)| (sessions.and_then(|sessions| sessions).unwrap_or_default(), meta)))
+    }
+
+    /// Lists all the sessions
+    pub fn list(&self) -> Box<dyn Future<Item = Vec<SessionEntry>, Error = Error>> {
+        Box::new(self.list_with(&QueryOptions::default()).map(|(sessions, _)| sessions))
+    }
+
+    /// Same as `list`, but allows blocking queries
+    pub fn list_with(&self, options: &QueryOptions) -> Box<dyn Future<Item = (Vec<SessionEntry>, QueryMeta), Error = Error>> {
+        Box::new(self.client.get_json::<Option<Vec<SessionEntry>>>("/v1/session/list", options)
+        .map(|(sessions, meta)| (sessions.and_then(|sessions| sessions).unwrap_or_default(), meta)))
+    }
+}
+
+/// Owns a session: renews it at half its TTL and destroys it when
+/// dropped.
+///
+/// Renewal failures are retried on the next tick. The session is
+/// considered lost once consul no longer knows about it, or once a whole
+/// TTL passed without a successful renewal since consul then invalidates it
+/// even if it cannot tell us.
+pub struct SessionKeeper {
+    id: String,
+    lost: Shared<oneshot::Receiver<()>>,
+    _stop: oneshot::Sender<()>,
+}
+
+impl SessionKeeper {
+    /// Creates a session as described by `session` and starts renewing it
+    pub fn create(client: &Client, session: &CreateSession) -> Box<dyn Future<Item = SessionKeeper, Error = Error>> {
+        let client = client.clone();
+        let ttl = session.ttl.as_ref().and_then(|ttl| parse_duration(ttl));
+        Box::new(client.session().create(session)
+        .and_then(move |id| SessionKeeper::keep(&client, id, ttl)))
+    }
+
+    /// Takes over an existing session, renewing it every half of `ttl`.
+    ///
+    /// Sessions without a TTL, or with a TTL of zero which consul reads
+    /// the same way, never expire and are only destroyed on drop.
+    pub fn keep(client: &Client, id: String, ttl: Option<Duration>) -> Result<SessionKeeper, Error> {
+        let ttl = ttl.filter(|ttl| *ttl > Duration::from_secs(0));
+        let (stop, stopped) = oneshot::channel::<()>();
+        let (lost, lost_rx) = oneshot::channel::<()>();
+
+        let renewals: Box<dyn Future<Item = (), Error = ()>> = match ttl {
+            Some(ttl) => {
+                let client = client.clone();
+                let id = id.clone();
+                let renewed_at = Rc::new(Cell::new(Instant::now()));
+                Box::new(Interval::new(ttl / 2, client.handle())?
+                .map_err(|_| ())
+                .for_each(move |_| {
+                    let renewed_at = renewed_at.clone();
+                    client.session().renew(&id).then(move |renewed| match renewed {
+                        Ok(Some(_)) => {
+                            renewed_at.set(Instant::now());
+                            Ok(())
+                        }
+                        Ok(None) => Err(()),
+                        Err(_) if renewed_at.get().elapsed() >= ttl => Err(()),
+                        Err(_) => Ok(()),
+                    })
+                }))
+            }
+            None => Box::new(future::empty()),
+        };
+
+        let handle = client.handle().clone();
+        let client = client.clone();
+        let session = id.clone();
+        let task = renewals.then(|_| Ok::<bool, ()>(false))
+            .select(stopped.then(|_| Ok(true)))
+            .map_err(|_| ())
+            .and_then(move |(stopped, _)| -> Box<dyn Future<Item = (), Error = ()>> {
+                if stopped {
+                    return Box::new(client.session().destroy(&session).then(|_| Ok(())));
+                }
+                let _ = lost.send(());
+                Box::new(future::ok(()))
+            });
+        handle.spawn(task);
+
+        Ok(SessionKeeper { id, lost: lost_rx.shared(), _stop: stop })
+    }
+
+    /// ID of the session
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Resolves once a renewal finds the session invalidated by consul, e.g.
+    /// after a failing health check, or once renewals have been failing for
+    /// a whole TTL
+    pub fn lost(&self) -> Box<dyn Future<Item = (), Error = ()>> {
+        Box::new(self.lost.clone().then(|_| Ok(())))
+    }
+}
+
+/// Parses durations the way consul formats them, e.g. "15s" or "1m30s"
+pub(crate) fn parse_duration(s: &str) -> Option<Duration> {
+    let mut total = Duration::from_secs(0);
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        let value: f64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit = rest.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(rest.len());
+        let nanos = match &rest[..unit] {
+            "h" => 3_600_000_000_000.0,
+            "m" => 60_000_000_000.0,
+            "s" => 1_000_000_000.0,
+            "ms" => 1_000_000.0,
+            "us" | "µs" => 1_000.0,
+            "ns" => 1.0,
+            _ => return None,
+        };
+        rest = &rest[unit..];
+        total += Duration::from_nanos((value * nanos) as u64);
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::{Core, Timeout};
+
+    use std::time::Duration;
+
+    use mock::{self, Reply};
+    use {Client, CreateSession, SessionBehavior};
+    use super::{parse_duration, SessionKeeper};
+
+    const ENTRY: &str = r#"{"ID":"adf4238a-882b-9ddc-4a9d-5b6758e4159e","Name":"leader","Node":"foobar",
+                            "LockDelay":15000000000,"Behavior":"release","TTL":"30s","NodeChecks":["serfHealth"],
+                            "ServiceChecks":null,"CreateIndex":1086449,"ModifyIndex":1086449}"#;
+
+    #[test]
+    fn session() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"adf4238a-882b-9ddc-4a9d-5b6758e4159e"}"#),
+            Reply::new(200, &format!("[{}]", ENTRY)),
+            Reply::new(404, "Session id 'adf4238a-882b-9ddc-4a9d-5b6758e4159e' not found"),
+            Reply::new(200, &format!("[{}]", ENTRY)),
+            Reply::new(200, "null"),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let create = CreateSession {
+            name: Some("leader".into()),
+            ttl: Some("30s".into()),
+            lock_delay: Some("15s".into()),
+            behavior: Some(SessionBehavior::Release),
+            ..Default::default()
+        };
+        let id = core.run(client.session().create(&create)).unwrap();
+        let req = requests.recv().unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("PUT", "/v1/session/create"));
+        assert_eq!(req.body, br#"{"Name":"leader","TTL":"30s","LockDelay":"15s","Behavior":"release"}"#.to_vec());
+        assert_eq!(id, "adf4238a-882b-9ddc-4a9d-5b6758e4159e");
+
+        let session = core.run(client.session().renew(&id)).unwrap().unwrap();
+        assert_eq!(requests.recv().unwrap().path, format!("/v1/session/renew/{}", id));
+        assert_eq!(session.lock_delay, 15_000_000_000);
+        assert_eq!(session.behavior, SessionBehavior::Release);
+
+        assert!(core.run(client.session().renew(&id)).unwrap().is_none());
+        requests.recv().unwrap();
+
+        let sessions = core.run(client.session().list()).unwrap();
+        assert_eq!(requests.recv().unwrap().path, "/v1/session/list");
+        assert_eq!(sessions[0].ttl, "30s");
+
+        assert!(core.run(client.session().info(&id)).unwrap().is_none());
+        assert_eq!(requests.recv().unwrap().path, format!("/v1/session/info/{}", id));
+
+        assert!(core.run(client.session().destroy(&id)).unwrap());
+        assert_eq!(requests.recv().unwrap().path, format!("/v1/session/destroy/{}", id));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("15s"), Some(Duration::from_secs(15)));
+        assert_eq!(parse_duration("1m30s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1.5h"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn keeper_renews_until_lost() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"adf4238a"}"#),
+            Reply::new(200, &format!("[{}]", ENTRY)),
+            Reply::new(404, "Session id 'adf4238a' not found"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let create = CreateSession { ttl: Some("100ms".into()), ..Default::default() };
+        let keeper = core.run(SessionKeeper::create(&client, &create)).unwrap();
+        assert_eq!(keeper.id(), "adf4238a");
+        core.run(keeper.lost()).unwrap();
+
+        let paths: Vec<_> = requests.iter().take(3).map(|req| req.path).collect();
+        assert_eq!(paths, vec!["/v1/session/create", "/v1/session/renew/adf4238a", "/v1/session/renew/adf4238a"]);
+    }
+
+    #[test]
+    fn keeper_lost_after_failing_for_a_ttl() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"adf4238a"}"#),
+            Reply::new(500, "rpc error"),
+            Reply::new(500, "rpc error"),
+            Reply::new(500, "rpc error"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let create = CreateSession { ttl: Some("100ms".into()), ..Default::default() };
+        let keeper = core.run(SessionKeeper::create(&client, &create)).unwrap();
+        core.run(keeper.lost()).unwrap();
+
+        let paths: Vec<_> = requests.iter().take(3).map(|req| req.path).collect();
+        assert_eq!(paths, vec!["/v1/session/create", "/v1/session/renew/adf4238a", "/v1/session/renew/adf4238a"]);
+    }
+
+    #[test]
+    fn keeper_does_not_renew_zero_ttl() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"adf4238a"}"#),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let create = CreateSession { ttl: Some("0s".into()), ..Default::default() };
+        let keeper = core.run(SessionKeeper::create(&client, &create)).unwrap();
+        requests.recv().unwrap();
+        core.run(Timeout::new(Duration::from_millis(50), &core.handle()).unwrap()).unwrap();
+        drop(keeper);
+        assert_eq!(mock::next_request(&mut core, &requests).path, "/v1/session/destroy/adf4238a");
+    }
+
+    #[test]
+    fn keeper_destroys_on_drop() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"ID":"adf4238a"}"#),
+            Reply::new(200, "true"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let keeper = core.run(SessionKeeper::create(&client, &CreateSession::default())).unwrap();
+        requests.recv().unwrap();
+        drop(keeper);
+        let req = mock::next_request(&mut core, &requests);
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("PUT", "/v1/session/destroy/adf4238a"));
+    }
+}
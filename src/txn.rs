@@ -0,0 +1,233 @@
+//! Transactions over several KV operations
+
+use base64;
+use futures::{Future, Stream};
+use hyper::{Method, StatusCode};
+use serde_json;
+
+use {Error, KVPair, KV, WriteOptions};
+
+/// A single operation of a transaction.
+///
+/// The `Check*` operations do not change anything, they make the whole
+/// transaction fail if the key is not in the expected state.
+#[derive(Debug, Clone)]
+pub enum TxnOp {
+    /// Writes a key
+    Set { key: String, value: Vec<u8>, flags: u64 },
+    /// Reads a key, the transaction fails if it does not exist
+    Get { key: String },
+    /// Deletes a key
+    Delete { key: String },
+    /// Writes a key if its `ModifyIndex` is still `index`, `0` if the key
+    /// must not exist yet
+    Cas { key: String, value: Vec<u8>, flags: u64, index: u64 },
+    /// Writes a key and acquires it for `session`
+    Lock { key: String, value: Vec<u8>, flags: u64, session: String },
+    /// Writes a key and releases it from `session`
+    Unlock { key: String, value: Vec<u8>, flags: u64, session: String },
+    /// Fails the transaction if the key's `ModifyIndex` is not `index`
+    CheckIndex { key: String, index: u64 },
+    /// Fails the transaction if the key is not held by `session`
+    CheckSession { key: String, session: String },
+    /// Deletes every key under a prefix
+    DeleteTree { prefix: String },
+    /// Deletes a key if its `ModifyIndex` is still `index`
+    DeleteCas { key: String, index: u64 },
+}
+
+/// The way a `TxnOp` goes over the wire
+#[derive(Serialize)]
+struct KVTxnOp<'a> {
+    #[serde(rename = "Verb")]
+    verb: &'static str,
+    #[serde(rename = "Key")]
+    key: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "Value")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "Flags")]
+    flags: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "Index")]
+    index: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "Session")]
+    session: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct TxnOpBody<'a> {
+    #[serde(rename = "KV")]
+    kv: KVTxnOp<'a>,
+}
+
+impl TxnOp {
+    /// Whether consul returns the key for this operation, all but the
+    /// deletes do
+    fn yields_key(&self) -> bool {
+        !matches!(*self, TxnOp::Delete { .. } | TxnOp::DeleteTree { .. } | TxnOp::DeleteCas { .. })
+    }
+
+    fn body(&self) -> TxnOpBody<'_> {
+        let op = |verb, key| KVTxnOp { verb, key, value: None, flags: None, index: None, session: None };
+        let kv = match *self {
+            TxnOp::Set { ref key, ref value, flags } => KVTxnOp {
+                value: Some(base64::encode(value)),
+                flags: Some(flags),
+                ..op("set", key)
+            },
+            TxnOp::Get { ref key } => op("get", key),
+            TxnOp::Delete { ref key } => op("delete", key),
+            TxnOp::Cas { ref key, ref value, flags, index } => KVTxnOp {
+                value: Some(base64::encode(value)),
+                flags: Some(flags),
+                index: Some(index),
+                ..op("cas", key)
+            },
+            TxnOp::Lock { ref key, ref value, flags, ref session } => KVTxnOp {
+                value: Some(base64::encode(value)),
+                flags: Some(flags),
+                session: Some(session),
+                ..op("lock", key)
+            },
+            TxnOp::Unlock { ref key, ref value, flags, ref session } => KVTxnOp {
+                value: Some(base64::encode(value)),
+                flags: Some(flags),
+                session: Some(session),
+                ..op("unlock", key)
+            },
+            TxnOp::CheckIndex { ref key, index } => KVTxnOp { index: Some(index), ..op("check-index", key) },
+            TxnOp::CheckSession { ref key, ref session } => KVTxnOp { session: Some(session), ..op("check-session", key) },
+            TxnOp::DeleteTree { ref prefix } => op("delete-tree", prefix),
+            TxnOp::DeleteCas { ref key, index } => KVTxnOp { index: Some(index), ..op("delete-cas", key) },
+        };
+        TxnOpBody { kv }
+    }
+}
+
+/// Why a transaction was rolled back
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxnError {
+    /// Position of the failed operation in the transaction
+    #[serde(rename = "OpIndex")]
+    pub op_index: usize,
+    #[serde(rename = "What")]
+    pub what: String,
+}
+
+#[derive(Deserialize)]
+struct TxnResult {
+    #[serde(rename = "KV")]
+    kv: Option<KVPair>,
+}
+
+/// Body of a transaction response, both parts are `null` when empty
+#[derive(Deserialize)]
+struct TxnResponse {
+    #[serde(default, rename = "Results")]
+    results: Option<Vec<TxnResult>>,
+    #[serde(default, rename = "Errors")]
+    errors: Option<Vec<TxnError>>,
+}
+
+impl<'a> KV<'a> {
+    /// Applies `ops` atomically, either all of them or none.
+    ///
+    /// Resolves to one entry per operation, in the order of `ops`: `Get`
+    /// yields the key with its value, the other writes and checks the key
+    /// without it and the deletes `None`. A rolled back transaction fails
+    /// with `Error::TxnRollback`.
+    pub fn txn(&self, ops: &[TxnOp]) -> Box<dyn Future<Item = Vec<Option<KVPair>>, Error = Error>> {
+        self.txn_with(ops, &WriteOptions::default())
+    }
+
+    /// Same as `txn`, with options such as a token of its own
+    pub fn txn_with(&self, ops: &[TxnOp], options: &WriteOptions) -> Box<dyn Future<Item = Vec<Option<KVPair>>, Error = Error>> {
+        let yields: Vec<_> = ops.iter().map(TxnOp::yields_key).collect();
+        let ops: Vec<_> = ops.iter().map(TxnOp::body).collect();
+        Box::new(self.client.request_json(Method::Put, "/v1/txn", options.token.as_deref(), ops)
+        .and_then(|resp| {
+            let status = resp.status();
+            resp.body().concat2().map(move |body| (status, body)).map_err(Error::from)
+        })
+        .and_then(move |(status, body)| {
+            if status == StatusCode::Conflict {
+                return match serde_json::from_slice::<TxnResponse>(&body) {
+                    Ok(response) => Err(Error::TxnRollback(response.errors.unwrap_or_default())),
+                    Err(_) => Err(Error::from_status(status, &body)),
+                };
+            }
+            if status != StatusCode::Ok {
+                return Err(Error::from_status(status, &body));
+            }
+            let response: TxnResponse = serde_json::from_slice(&body)?;
+            let mut results = response.results.unwrap_or_default().into_iter();
+            Ok(yields.into_iter().map(|yields| {
+                if yields {
+                    results.next().and_then(|result| result.kv)
+                } else {
+                    None
+                }
+            }).collect())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use tokio_core::reactor::Core;
+
+    use mock::{self, Reply};
+    use {Client, Error, TxnOp};
+
+    #[test]
+    fn txn() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, r#"{"Results":[{"KV":{"LockIndex":0,"Key":"config/a","Flags":0,"Value":null,"CreateIndex":5,"ModifyIndex":5}},
+                                           {"KV":{"LockIndex":0,"Key":"config/b","Flags":0,"Value":"Mg==","CreateIndex":3,"ModifyIndex":4}},
+                                           {"KV":{"LockIndex":1,"Key":"config/lock","Flags":0,"Value":null,"CreateIndex":2,"ModifyIndex":2,"Session":"s1"}}],
+                                "Errors":null}"#),
+            Reply::new(409, r#"{"Results":null,"Errors":[{"OpIndex":0,"What":"failed to set key \"config/a\", index is stale"}]}"#),
+            Reply::new(409, "txn too large"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let pairs = core.run(client.kv().txn(&[
+            TxnOp::Cas { key: "config/a".into(), value: b"1".to_vec(), flags: 0, index: 4 },
+            TxnOp::Get { key: "config/b".into() },
+            TxnOp::CheckSession { key: "config/lock".into(), session: "s1".into() },
+            TxnOp::DeleteTree { prefix: "config/old/".into() },
+        ])).unwrap();
+        let req = requests.recv().unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("PUT", "/v1/txn"));
+        let body: serde_json::Value = serde_json::from_slice(&req.body).unwrap();
+        assert_eq!(body.to_string(), concat!(
+            r#"[{"KV":{"Flags":0,"Index":4,"Key":"config/a","Value":"MQ==","Verb":"cas"}},"#,
+            r#"{"KV":{"Key":"config/b","Verb":"get"}},"#,
+            r#"{"KV":{"Key":"config/lock","Session":"s1","Verb":"check-session"}},"#,
+            r#"{"KV":{"Key":"config/old/","Verb":"delete-tree"}}]"#,
+        ));
+        assert_eq!(pairs.len(), 4);
+        assert_eq!(pairs[0].as_ref().unwrap().key, "config/a");
+        assert_eq!(pairs[1].as_ref().unwrap().value, b"2".to_vec());
+        assert_eq!(pairs[2].as_ref().unwrap().session.as_deref(), Some("s1"));
+        assert!(pairs[3].is_none());
+
+        let rolled_back = core.run(client.kv().txn(&[
+            TxnOp::Cas { key: "config/a".into(), value: b"1".to_vec(), flags: 0, index: 4 },
+        ]));
+        match rolled_back {
+            Err(Error::TxnRollback(errors)) => {
+                assert_eq!(errors[0].op_index, 0);
+                assert!(errors[0].what.contains("index is stale"));
+            }
+            other => panic!("expected a rollback, got {:?}", other),
+        }
+
+        let refused = core.run(client.kv().txn(&[TxnOp::Get { key: "config/a".into() }]));
+        match refused {
+            Err(Error::Conflict(body)) => assert_eq!(body, "txn too large"),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+}
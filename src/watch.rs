@@ -0,0 +1,183 @@
+//! Streams of changes built on blocking queries
+
+use std::time::Duration;
+
+use futures::{Async, Future, Poll, Stream};
+use tokio_core::reactor::{Handle, Timeout};
+
+use {Error, QueryMeta, QueryOptions};
+
+type Query<T> = Box<dyn Future<Item = (T, QueryMeta), Error = Error>>;
+type QueryFn<T> = Box<dyn Fn(&QueryOptions) -> Query<T>>;
+
+enum State<T> {
+    Idle,
+    Querying(Query<T>),
+    Sleeping(Timeout),
+}
+
+/// Stream yielding the result of a query every time its index changes.
+///
+/// The first result is yielded right away, after that the query is
+/// repeated as a blocking query and only results with a new
+/// `X-Consul-Index` come out. An index going backwards (e.g. after a
+/// snapshot restore) restarts the watch from scratch.
+///
+/// Errors do not end the stream: the failure is yielded and the query is
+/// retried after an exponentially growing delay. Note that combinators such
+/// as `for_each` stop at the first error.
+pub struct Watch<T> {
+    query: QueryFn<T>,
+    options: QueryOptions,
+    handle: Handle,
+    state: State<T>,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+}
+
+impl<T> Watch<T> {
+    /// Watches the result of an arbitrary read call.
+    ///
+    /// `query` is called with `options` carrying the index to block on.
+    pub fn new<F>(handle: &Handle, options: QueryOptions, query: F) -> Self
+        where F: Fn(&QueryOptions) -> Query<T> + 'static
+    {
+        let min_backoff = Duration::from_secs(1);
+        Watch {
+            query: Box::new(query),
+            options,
+            handle: handle.clone(),
+            state: State::Idle,
+            min_backoff,
+            max_backoff: Duration::from_secs(60),
+            backoff: min_backoff,
+        }
+    }
+
+    /// Sets the bounds of the delay between retries of failed queries,
+    /// defaults to 1 second up to 1 minute
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max;
+        self.backoff = min;
+        self
+    }
+
+    /// Index of the last result yielded
+    pub fn index(&self) -> Option<u64> {
+        self.options.index
+    }
+}
+
+impl<T> Stream for Watch<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<T>, Error> {
+        loop {
+            let result = match self.state {
+                State::Idle => {
+                    self.state = State::Querying((self.query)(&self.options));
+                    continue;
+                }
+                State::Sleeping(ref mut timeout) => {
+                    if let Ok(Async::NotReady) = timeout.poll() {
+                        return Ok(Async::NotReady);
+                    }
+                    self.state = State::Idle;
+                    continue;
+                }
+                State::Querying(ref mut query) => match query.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(result)) => Ok(result),
+                    Err(e) => Err(e),
+                },
+            };
+
+            match result {
+                Ok((value, meta)) => {
+                    self.state = State::Idle;
+                    self.backoff = self.min_backoff;
+                    // an index of 0 would turn the next query into a non-blocking one
+                    let index = ::std::cmp::max(meta.last_index, 1);
+                    match self.options.index {
+                        Some(last) if index == last => continue,
+                        Some(last) if index < last => {
+                            self.options.index = None;
+                            continue;
+                        }
+                        _ => {
+                            self.options.index = Some(index);
+                            return Ok(Async::Ready(Some(value)));
+                        }
+                    }
+                }
+                Err(e) => {
+                    let delay = self.backoff;
+                    self.backoff = ::std::cmp::min(self.backoff * 2, self.max_backoff);
+                    self.state = match Timeout::new(delay, &self.handle) {
+                        Ok(timeout) => State::Sleeping(timeout),
+                        Err(_) => State::Idle,
+                    };
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::Stream;
+    use tokio_core::reactor::Core;
+
+    use mock::{self, KvEntry, Reply};
+    use {Client, QueryOptions};
+
+    #[test]
+    fn yields_on_index_change() {
+        let (url, requests) = mock::serve(vec![
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("config").value("a")])).header("X-Consul-Index", "5"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("config").value("a")])).header("X-Consul-Index", "5"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("config").value("b")])).header("X-Consul-Index", "7"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("config").value("b")])).header("X-Consul-Index", "3"),
+            Reply::new(200, &mock::kv_list(&[KvEntry::new("config").value("c")])).header("X-Consul-Index", "3"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let watch = client.kv().watch("config", &QueryOptions::default());
+        let values = core.run(watch.take(3).collect()).unwrap();
+        let values: Vec<_> = values.into_iter().map(|pair| pair.unwrap().value).collect();
+        assert_eq!(values, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+
+        let paths: Vec<_> = requests.iter().take(5).map(|req| req.path).collect();
+        assert_eq!(paths, vec![
+            "/v1/kv/config",
+            "/v1/kv/config?index=5",
+            "/v1/kv/config?index=5",
+            "/v1/kv/config?index=7",
+            // index went backwards, start over
+            "/v1/kv/config",
+        ]);
+    }
+
+    #[test]
+    fn retries_after_errors() {
+        let (url, _requests) = mock::serve(vec![
+            Reply::new(500, "No cluster leader"),
+            Reply::new(200, "[]").header("X-Consul-Index", "9"),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle(), &url).unwrap();
+
+        let watch = client.kv().watch_prefix("config/", &QueryOptions::default())
+            .backoff(Duration::from_millis(10), Duration::from_millis(100));
+        let results = core.run(watch.then(Ok::<_, ()>).take(2).collect()).unwrap();
+        assert!(results[0].is_err());
+        assert!(results[1].as_ref().unwrap().is_empty());
+    }
+}